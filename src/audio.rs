@@ -0,0 +1,269 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Zero crossings of the sinc on each side of the filter's centre.
+// More gives a sharper cutoff for more CPU.
+const ZERO_CROSSINGS: usize = 16;
+
+// Cutoff as a fraction of the lower Nyquist frequency, leaving room
+// for the transition band so that it does not alias
+const ROLLOFF: f64 = 0.9;
+
+/// Average interleaved `channels`-channel audio down to mono
+pub fn downmix(interleaved: &[i16], channels: usize) -> Vec<i16> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| {
+            let sum: i32 = frame.iter().map(|&s| s as i32).sum();
+
+            (sum / channels as i32) as i16
+        })
+        .collect()
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Low-pass filter for resampling by `up / down`, split into `up`
+/// phases so that only the taps that land on real input samples are
+/// ever computed
+#[derive(Debug)]
+struct PolyphaseFilter {
+    up: usize,
+    down: usize,
+    // phases[p][k] applies to the input sample k before the newest
+    phases: Vec<Vec<f32>>
+}
+
+impl PolyphaseFilter {
+    fn new(up: usize, down: usize) -> PolyphaseFilter {
+        // Cutoff in cycles per sample of the upsampled signal
+        let cutoff = 0.5 / up.max(down) as f64 * ROLLOFF;
+        let half_len = (ZERO_CROSSINGS as f64 / (2.0 * cutoff)).ceil() as usize;
+        let len = 2 * half_len + 1;
+
+        // Windowed sinc, with a Blackman window
+        let mut proto: Vec<f64> = (0..len)
+            .map(|j| {
+                let x = j as f64 - half_len as f64;
+                let w = j as f64 / (len - 1) as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+
+                2.0 * cutoff * sinc(2.0 * cutoff * x) * window
+            })
+            .collect();
+
+        // Each phase should pass DC unchanged, so the whole filter
+        // sums to `up`
+        let sum: f64 = proto.iter().sum();
+        for h in proto.iter_mut() {
+            *h *= up as f64 / sum;
+        }
+
+        let taps = len.div_ceil(up);
+        let phases = (0..up)
+            .map(|p| {
+                (0..taps)
+                    .map(|k| proto.get(p + k * up).copied().unwrap_or(0.0) as f32)
+                    .collect()
+            })
+            .collect();
+
+        PolyphaseFilter { up, down, phases }
+    }
+
+    fn taps(&self) -> usize {
+        self.phases[0].len()
+    }
+}
+
+/// Streaming windowed-sinc resampler between two fixed rates. Audio
+/// can be fed in packets of any size; the filter state carries over
+/// from one to the next.
+///
+/// Clones share the filter but not the state, so a fresh resampler
+/// can be cloned cheaply for each stream.
+#[derive(Debug)]
+pub struct Resampler {
+    filter: Arc<PolyphaseFilter>,
+    // Input not yet fully used, preceded by enough older samples to
+    // fill the filter
+    input: Vec<f32>,
+    // Position of the next output in the upsampled signal, relative
+    // to the start of `input`
+    time: usize
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Resampler {
+        let g = gcd(from, to);
+        let filter = PolyphaseFilter::new((to / g) as usize, (from / g) as usize);
+
+        Resampler::with_filter(Arc::new(filter))
+    }
+
+    fn with_filter(filter: Arc<PolyphaseFilter>) -> Resampler {
+        let taps = filter.taps();
+
+        Resampler {
+            input: vec![0.0; taps - 1],
+            time: (taps - 1) * filter.up,
+            filter
+        }
+    }
+
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        let PolyphaseFilter { up, down, ref phases } = *self.filter;
+        let taps = phases[0].len();
+
+        self.input.extend(samples.iter().map(|&s| s as f32));
+
+        let mut out = Vec::with_capacity(samples.len() * up / down + 1);
+
+        while self.time / up < self.input.len() {
+            let newest = self.time / up;
+            let phase = &phases[self.time % up];
+
+            let y: f32 = phase
+                .iter()
+                .zip(self.input[newest + 1 - taps..=newest].iter().rev())
+                .map(|(h, x)| h * x)
+                .sum();
+
+            out.push(y.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            self.time += down;
+        }
+
+        // Keep only what the next output still needs
+        let used = (self.time / up + 1).saturating_sub(taps).min(self.input.len());
+        self.input.drain(..used);
+        self.time -= used * up;
+
+        out
+    }
+
+    /// Forget the stream so far
+    pub fn reset(&mut self) {
+        *self = Resampler::with_filter(self.filter.clone());
+    }
+}
+
+impl Clone for Resampler {
+    fn clone(&self) -> Resampler {
+        Resampler::with_filter(self.filter.clone())
+    }
+}
+
+// Levels are in dB relative to full scale
+const INITIAL_NOISE_FLOOR: f32 = -60.0;
+// Quieter than this is never speech, however quiet the background
+const MIN_SPEECH_LEVEL: f32 = -50.0;
+// How far above the background audio must be to count as speech
+const SPEECH_MARGIN: f32 = 9.0;
+// How fast the background estimate may rise, so that a noisy open
+// mic is learned within several seconds but speech is not
+const NOISE_FLOOR_RISE_PER_SEC: f32 = 3.0;
+
+// Speech needed to start an utterance, so that clicks do not
+const ONSET_SECS: f32 = 0.06;
+// Silence needed to end one, so that pauses between words do not
+const HANGOVER_SECS: f32 = 0.5;
+
+fn level(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+
+    let power = samples.iter().map(|&s| (s as f32 / 32768.0).powi(2)).sum::<f32>() / samples.len() as f32;
+
+    10.0 * power.max(1e-10).log10()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceActivity {
+    /// Speech has started, including the frames since its onset
+    Start,
+    /// Silence has gone on for long enough to end the utterance
+    End
+}
+
+/// Energy-based voice activity detector. Speech is audio noticeably
+/// louder than the background noise, whose level is tracked as it
+/// changes, so a constant hum or fan is ignored once learned.
+#[derive(Debug, Clone)]
+pub struct VoiceActivityDetector {
+    sample_rate: f32,
+    noise_floor: f32,
+    speaking: bool,
+    // Length of the current run of speech or silence frames, in
+    // seconds, whichever is the opposite of `speaking`
+    run: f32
+}
+
+impl VoiceActivityDetector {
+    pub fn new(sample_rate: f32) -> VoiceActivityDetector {
+        VoiceActivityDetector {
+            sample_rate,
+            noise_floor: INITIAL_NOISE_FLOOR,
+            speaking: false,
+            run: 0.0
+        }
+    }
+
+    /// Feed one frame of mono audio. Returns a change in activity if
+    /// this frame caused one.
+    pub fn process(&mut self, frame: &[i16]) -> Option<VoiceActivity> {
+        let secs = frame.len() as f32 / self.sample_rate;
+        let level = level(frame);
+        let is_speech = level >= MIN_SPEECH_LEVEL && level >= self.noise_floor + SPEECH_MARGIN;
+
+        // Follow the background down at once, but up only slowly.
+        // This rises during speech too, so that constant noise loud
+        // enough to count as speech is eventually learned; the quieter
+        // gaps between words pull it back down.
+        if level < self.noise_floor {
+            self.noise_floor = level.max(INITIAL_NOISE_FLOOR);
+        } else {
+            self.noise_floor = (self.noise_floor + NOISE_FLOOR_RISE_PER_SEC * secs).min(level);
+        }
+
+        if is_speech != self.speaking {
+            self.run += secs;
+        } else {
+            self.run = 0.0;
+        }
+
+        match (self.speaking, self.run) {
+            (false, run) if run >= ONSET_SECS => {
+                self.speaking = true;
+                self.run = 0.0;
+                Some(VoiceActivity::Start)
+            },
+            (true, run) if run >= HANGOVER_SECS => {
+                self.speaking = false;
+                self.run = 0.0;
+                Some(VoiceActivity::End)
+            },
+            _ => None
+        }
+    }
+
+    /// Forget the current utterance, keeping the background estimate
+    pub fn reset(&mut self) {
+        self.speaking = false;
+        self.run = 0.0;
+    }
+}
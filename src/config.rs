@@ -1,15 +1,78 @@
 use std::error::Error;
 use std::path::{PathBuf, Path};
 use std::fs::File;
+use std::collections::HashMap;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub bot_token: String,
     pub application_id: u64,
+    // Model for guilds without a language, or whose language has no
+    // model of its own
     pub model_path: PathBuf,
+    // Models by language code
+    #[serde(default)]
+    pub models: HashMap<String, PathBuf>,
+    // Sample rates by model directory, for models whose mfcc.conf
+    // does not give the right one
+    #[serde(default)]
+    pub model_sample_rates: HashMap<PathBuf, u32>,
+    // Seconds a model may go unused before it is unloaded
+    #[serde(default = "default_model_idle_secs")]
+    pub model_idle_secs: u64,
+    // Longest a live caption may cover, in seconds, before it is
+    // posted even though the speaker has not paused
+    #[serde(default = "default_max_caption_secs")]
+    pub max_caption_secs: f32,
+    // Number of speakers recognized at once; defaults to the number
+    // of CPUs
+    pub recognition_threads: Option<usize>,
+    // libvosk to load; found through the normal library search path
+    // if left out
+    pub vosk_library_path: Option<PathBuf>,
+    // libvosk's log verbosity; less than 0 hides its info messages
+    pub vosk_log_level: Option<i32>,
     pub webhook_url: String,
-    pub db_path: PathBuf
+    pub db_path: PathBuf,
+    // Where calls are recorded when asked to; recording is disabled if
+    // left out
+    pub recording_dir: Option<PathBuf>,
+    // Days recordings are kept before being deleted
+    #[serde(default = "default_recording_retention_days")]
+    pub recording_retention_days: u64,
+    // Where a JSON lines transcript of every captioning session is
+    // written; none are if left out
+    pub transcript_dir: Option<PathBuf>,
+    // Whether to ask Discord for member events, which keep speakers'
+    // names and avatars up to date. Their intent is privileged, so it
+    // must be enabled for the bot in the developer portal first, or
+    // the bot cannot connect; without them, profiles are looked up
+    // again now and then instead.
+    #[serde(default)]
+    pub member_events: bool,
+    // Optional vosk speaker model for telling apart people sharing a
+    // microphone
+    pub spk_model_path: Option<PathBuf>,
+    // ggml whisper model for guilds that pick whisper for message
+    // transcription
+    #[cfg(feature = "whisper")]
+    pub whisper_model_path: Option<PathBuf>,
+    // Whisper language code; detected per message if left out
+    #[cfg(feature = "whisper")]
+    pub whisper_language: Option<String>
+}
+
+fn default_model_idle_secs() -> u64 {
+    600
+}
+
+fn default_max_caption_secs() -> f32 {
+    20.0
+}
+
+fn default_recording_retention_days() -> u64 {
+    7
 }
 
 impl Config {
@@ -1,10 +1,11 @@
 use sqlx::{SqlitePool, Result as SqlResult, ConnectOptions};
-use std::convert::TryInto;
 use std::path::Path;
 
 use std::error::Error;
 
-use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+
+use crate::speech::{Endpointer, EndpointerDelays, EndpointerMode};
 
 pub struct BotDb {
     conn: SqlitePool
@@ -37,6 +38,54 @@ impl BotDb {
             .execute(&self.conn)
             .await?;
 
+        sqlx::query![
+            "CREATE TABLE IF NOT EXISTS channel_vocabularies (
+                 channel_id BIGINT PRIMARY KEY,
+                 guild_id BIGINT NOT NULL,
+                 phrases TEXT NOT NULL
+             )"]
+            .execute(&self.conn)
+            .await?;
+
+        sqlx::query![
+            "CREATE TABLE IF NOT EXISTS voiceprints (
+                 guild_id BIGINT NOT NULL,
+                 user_id BIGINT NOT NULL,
+                 microphone_id BIGINT NOT NULL,
+                 xvector TEXT NOT NULL,
+                 PRIMARY KEY (guild_id, user_id)
+             )"]
+            .execute(&self.conn)
+            .await?;
+
+        sqlx::query![
+            "CREATE TABLE IF NOT EXISTS transcription_engines (
+                 guild_id BIGINT PRIMARY KEY,
+                 engine TEXT NOT NULL
+             )"]
+            .execute(&self.conn)
+            .await?;
+
+        sqlx::query![
+            "CREATE TABLE IF NOT EXISTS glossary (
+                 guild_id BIGINT NOT NULL,
+                 term TEXT NOT NULL,
+                 PRIMARY KEY (guild_id, term)
+             )"]
+            .execute(&self.conn)
+            .await?;
+
+        sqlx::query![
+            "CREATE TABLE IF NOT EXISTS endpointers (
+                 guild_id BIGINT PRIMARY KEY,
+                 mode TEXT NOT NULL,
+                 start_max REAL,
+                 end_silence REAL,
+                 max_length REAL
+             )"]
+            .execute(&self.conn)
+            .await?;
+
         Ok(())
     }
 
@@ -73,7 +122,7 @@ impl BotDb {
                 Ok(
                     GuildConfig {
                         caption_channel: row.caption_channel.map(|id| ChannelId(id as u64)),
-                        lang: row.lang.map(|l| l.as_bytes().try_into().unwrap())
+                        lang: row.lang
                     }
                 )
             })
@@ -102,24 +151,235 @@ impl BotDb {
     pub async fn set_lang(
         &self,
         guild: impl Into<GuildId>,
-        lang: Option<[u8; 3]>
+        lang: Option<&str>
     ) -> SqlResult<()>
     {
         let g = guild.into().0 as i64;
-        let l = lang.as_ref().map(|lang| std::str::from_utf8(lang).unwrap());
         sqlx::query![
-            "UPDATE guilds SET caption_channel = ?1
-                 WHERE guild_id = ?2",
+            "INSERT INTO guilds (guild_id, lang) VALUES (?1, ?2)
+                 ON CONFLICT (guild_id) DO UPDATE SET lang = excluded.lang",
             g,
-            l]
+            lang]
             .execute(&self.conn)
             .await?;
 
         Ok(())
     }
+
+    /// The restricted vocabulary for captions in a voice channel, if
+    /// one has been set
+    pub async fn channel_vocabulary(&self, channel: impl Into<ChannelId>) -> SqlResult<Option<Vec<String>>> {
+        let c = channel.into().0 as i64;
+        let row = sqlx::query![
+            "SELECT phrases FROM channel_vocabularies WHERE channel_id = ?",
+            c]
+            .fetch_optional(&self.conn)
+            .await?;
+
+        row.map(|row| serde_json::from_str(&row.phrases).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+            .transpose()
+    }
+
+    pub async fn set_channel_vocabulary(
+        &self,
+        guild: impl Into<GuildId>,
+        channel: impl Into<ChannelId>,
+        phrases: Option<&[String]>
+    ) -> SqlResult<()>
+    {
+        let g = guild.into().0 as i64;
+        let c = channel.into().0 as i64;
+
+        match phrases {
+            Some(phrases) => {
+                let p = serde_json::to_string(phrases).unwrap();
+                sqlx::query![
+                    "INSERT INTO channel_vocabularies (channel_id, guild_id, phrases) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (channel_id) DO UPDATE SET phrases = excluded.phrases",
+                    c,
+                    g,
+                    p]
+                    .execute(&self.conn)
+                    .await?;
+            },
+            None => {
+                sqlx::query![
+                    "DELETE FROM channel_vocabularies WHERE channel_id = ?",
+                    c]
+                    .execute(&self.conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All enrolled speaker x-vectors in a guild, with the microphone
+    /// each was enrolled on
+    pub async fn voiceprints(&self, guild: impl Into<GuildId>) -> SqlResult<Vec<(UserId, UserId, Vec<f32>)>> {
+        let g = guild.into().0 as i64;
+        let rows = sqlx::query![
+            "SELECT user_id, microphone_id, xvector FROM voiceprints WHERE guild_id = ?",
+            g]
+            .fetch_all(&self.conn)
+            .await?;
+
+        rows
+            .into_iter()
+            .map(|row| {
+                let xvector = serde_json::from_str(&row.xvector)
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+                Ok((UserId(row.user_id as u64), UserId(row.microphone_id as u64), xvector))
+            })
+            .collect()
+    }
+
+    pub async fn set_voiceprint(
+        &self,
+        guild: impl Into<GuildId>,
+        user: impl Into<UserId>,
+        microphone: impl Into<UserId>,
+        xvector: &[f32]
+    ) -> SqlResult<()>
+    {
+        let g = guild.into().0 as i64;
+        let u = user.into().0 as i64;
+        let m = microphone.into().0 as i64;
+        let x = serde_json::to_string(xvector).unwrap();
+        sqlx::query![
+            "INSERT INTO voiceprints (guild_id, user_id, microphone_id, xvector) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (guild_id, user_id) DO UPDATE
+                 SET microphone_id = excluded.microphone_id, xvector = excluded.xvector",
+            g,
+            u,
+            m,
+            x]
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The engine a guild has picked for message transcription, if
+    /// not the default
+    pub async fn transcription_engine(&self, guild: impl Into<GuildId>) -> SqlResult<Option<String>> {
+        let g = guild.into().0 as i64;
+        let row = sqlx::query![
+            "SELECT engine FROM transcription_engines WHERE guild_id = ?",
+            g]
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(row.map(|row| row.engine))
+    }
+
+    pub async fn set_transcription_engine(
+        &self,
+        guild: impl Into<GuildId>,
+        engine: &str
+    ) -> SqlResult<()>
+    {
+        let g = guild.into().0 as i64;
+        sqlx::query![
+            "INSERT INTO transcription_engines (guild_id, engine) VALUES (?1, ?2)
+                 ON CONFLICT (guild_id) DO UPDATE SET engine = excluded.engine",
+            g,
+            engine]
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A guild's endpointing settings for live captions
+    pub async fn endpointer(&self, guild: impl Into<GuildId>) -> SqlResult<Endpointer> {
+        let g = guild.into().0 as i64;
+        let row = sqlx::query![
+            "SELECT mode, start_max, end_silence, max_length FROM endpointers WHERE guild_id = ?",
+            g]
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(
+            row.map(|row| Endpointer {
+                mode: EndpointerMode::from_name(&row.mode).unwrap_or_default(),
+                delays: match (row.start_max, row.end_silence, row.max_length) {
+                    (Some(start_max), Some(end), Some(max)) => Some(EndpointerDelays {
+                        start_max: start_max as f32,
+                        end: end as f32,
+                        max: max as f32
+                    }),
+                    _ => None
+                }
+            })
+            .unwrap_or_default()
+        )
+    }
+
+    pub async fn set_endpointer(&self, guild: impl Into<GuildId>, endpointer: &Endpointer) -> SqlResult<()> {
+        let g = guild.into().0 as i64;
+        let mode = endpointer.mode.name();
+        let start_max = endpointer.delays.map(|d| d.start_max as f64);
+        let end = endpointer.delays.map(|d| d.end as f64);
+        let max = endpointer.delays.map(|d| d.max as f64);
+        sqlx::query![
+            "INSERT INTO endpointers (guild_id, mode, start_max, end_silence, max_length) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (guild_id) DO UPDATE SET
+                     mode = excluded.mode,
+                     start_max = excluded.start_max,
+                     end_silence = excluded.end_silence,
+                     max_length = excluded.max_length",
+            g,
+            mode,
+            start_max,
+            end,
+            max]
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A guild's glossary of names and jargon, in alphabetical order
+    pub async fn glossary(&self, guild: impl Into<GuildId>) -> SqlResult<Vec<String>> {
+        let g = guild.into().0 as i64;
+        let rows = sqlx::query![
+            "SELECT term FROM glossary WHERE guild_id = ? ORDER BY term",
+            g]
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.term).collect())
+    }
+
+    /// Returns false if the term was already in the glossary
+    pub async fn add_glossary_term(&self, guild: impl Into<GuildId>, term: &str) -> SqlResult<bool> {
+        let g = guild.into().0 as i64;
+        let res = sqlx::query![
+            "INSERT OR IGNORE INTO glossary (guild_id, term) VALUES (?1, ?2)",
+            g,
+            term]
+            .execute(&self.conn)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Returns false if the term was not in the glossary
+    pub async fn remove_glossary_term(&self, guild: impl Into<GuildId>, term: &str) -> SqlResult<bool> {
+        let g = guild.into().0 as i64;
+        let res = sqlx::query![
+            "DELETE FROM glossary WHERE guild_id = ?1 AND term = ?2",
+            g,
+            term]
+            .execute(&self.conn)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
 }
 
 pub struct GuildConfig {
     pub caption_channel: Option<ChannelId>,
-    pub lang: Option<[u8; 3]>
+    pub lang: Option<String>
 }
@@ -0,0 +1,86 @@
+// Longest frame Opus produces, in 48kHz samples per channel
+const MAX_FRAME_LEN: u32 = 5760;
+
+/// Fills in silence for one SSRC's lost packets, so that its audio
+/// keeps time. Songbird decodes packets as they arrive and gives no
+/// audio for any that arrive after a later one, so nothing would be
+/// gained by holding packets back to put them in order: each is passed
+/// on as soon as it arrives, and late ones are dropped, their time
+/// having already been filled in.
+pub struct GapFiller {
+    channels: usize,
+    // Extended (never wrapping) sequence number of the next packet
+    // expected, once the first has arrived
+    next: Option<u64>,
+    // Timestamp and length of the last packet passed on
+    last: Option<(u32, usize)>
+}
+
+impl GapFiller {
+    pub fn new(channels: usize) -> GapFiller {
+        GapFiller {
+            channels,
+            next: None,
+            last: None
+        }
+    }
+
+    // The extended sequence number closest to the next expected one
+    fn extend(&self, sequence: u16) -> u64 {
+        match self.next {
+            Some(next) => {
+                let delta = sequence.wrapping_sub(next as u16) as i16;
+
+                (next as i64 + delta as i64).max(0) as u64
+            },
+            None => sequence as u64 + (1 << 16)
+        }
+    }
+
+    /// Add a packet and return its audio, preceded by silence for any
+    /// packets lost before it, or `None` if it came too late. `audio`
+    /// is `None` if the packet could not be decoded; its time is then
+    /// filled with silence too.
+    pub fn push(&mut self, sequence: u16, timestamp: u32, audio: Option<&[i16]>) -> Option<Vec<i16>> {
+        let seq = self.extend(sequence);
+        let next = *self.next.get_or_insert(seq);
+
+        if seq < next {
+            return None;
+        }
+
+        self.next = Some(seq + 1);
+
+        Some(self.fill(timestamp, audio.unwrap_or_default(), seq - next))
+    }
+
+    // `audio`, preceded by silence for `missing` lost packets before it
+    fn fill(&mut self, timestamp: u32, audio: &[i16], missing: u64) -> Vec<i16> {
+        let frame_len = self.last.map_or(audio.len(), |(_, len)| len);
+
+        let silence = match self.last {
+            Some((last_ts, last_len)) if missing > 0 => {
+                // The timestamp says exactly how much time is missing,
+                // unless the sender has gone strange
+                let elapsed = timestamp.wrapping_sub(last_ts).min(MAX_FRAME_LEN * (missing as u32 + 1));
+
+                (elapsed as usize * self.channels).saturating_sub(last_len)
+            },
+            _ => missing as usize * frame_len
+        };
+
+        let audio_len = if audio.is_empty() { frame_len } else { audio.len() };
+
+        let mut out = vec![0; silence];
+
+        if audio.is_empty() {
+            out.resize(silence + audio_len, 0);
+        } else {
+            out.extend_from_slice(audio);
+        }
+
+        self.last = Some((timestamp, audio_len));
+
+        out
+    }
+}
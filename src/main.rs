@@ -7,6 +7,8 @@ use std::ffi::{CString, CStr};
 use once_cell::sync::OnceCell;
 use std::error::Error;
 use std::env;
+use std::collections::HashMap;
+use std::time::Duration;
 
 use tokio::sync::Mutex as TokioMutex;
 
@@ -17,14 +19,19 @@ use serenity::{
         id::{
             GuildId,
             MessageId,
+            UserId,
         },
         webhook::Webhook,
         channel::{GuildChannel, Channel},
+        guild::{Guild, Member, UnavailableGuild},
+        user::User,
+        permissions::Permissions,
         application::command::CommandType,
         interactions::{
             application_command::{
                 ApplicationCommand,
                 ApplicationCommandInteraction,
+                ApplicationCommandInteractionDataOption,
                 ApplicationCommandInteractionDataOptionValue,
                 ApplicationCommandOptionType,
             },
@@ -49,16 +56,40 @@ use serenity::{
 use songbird::{CoreEvent, SerenityInit};
 
 mod vosk;
+mod speech;
+#[cfg(feature = "whisper")]
+mod whisper;
+use speech::{SpeechBackend, SpeechError, SessionOptions, Endpointer, EndpointerDelays, EndpointerMode};
+mod workers;
+mod audio;
+mod recording;
+mod gaps;
+mod utterance;
+mod profiles;
+mod output;
+use output::{CaptionOutput, Destination};
+mod sinks;
+use sinks::{CaptionSink, MessageSink, JsonlSink};
+use profiles::ProfileCache;
+use workers::RecognitionPool;
 mod voice_recv;
 mod config;
 use config::Config;
 mod db;
 use db::BotDb;
+mod models;
+use models::ModelRegistry;
 
-static MODEL: OnceCell<vosk::Model> = OnceCell::new();
+static SPK_MODEL: OnceCell<vosk::SpkModel> = OnceCell::new();
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
 
+/// Number of candidate readings requested for message transcripts
+const TRANSCRIPT_ALTERNATIVES: usize = 3;
+
+/// How long `/enroll` waits for the speaker to say something
+const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 enum BotError<M> {
     UserMessage(M),
@@ -88,27 +119,80 @@ fn get_config() -> Result<&'static Config, Box<dyn Error + Send + Sync>> {
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let config = get_config()?;
 
-    // Load the model before connecting because it is slow; check
-    // model exists because VOSK error reporting is bad
-    if config.model_path.exists() {
-        MODEL.set(
-            vosk::Model::new(&config.model_path)
-                .ok_or_else(|| format!("Could not create vosk model from {:?}", config.model_path))?
+    if let Err(e) = vosk::sys::load(config.vosk_library_path.as_deref()) {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    }
+
+    if let Some(level) = config.vosk_log_level {
+        vosk::log::set_level(level);
+    }
+
+    let models = Arc::new(
+        ModelRegistry::new(
+            config.model_path.clone(),
+            config.models.clone(),
+            config.model_sample_rates.clone(),
+            Duration::from_secs(config.model_idle_secs)));
+
+    // Models are loaded lazily, so check them up front to catch typos
+    // in the config at startup
+    for path in models.model_paths() {
+        if let Err(e) = vosk::check_model_dir(path) {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+
+    tokio::spawn(models.clone().run_unloader());
+
+    if let Some(dir) = &config.recording_dir {
+        tokio::spawn(
+            recording::run_cleanup(
+                dir.clone(),
+                Duration::from_secs(config.recording_retention_days * 24 * 60 * 60)));
+    }
+
+    let pool = Arc::new(RecognitionPool::new(
+        config
+            .recognition_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))));
+
+    if let Some(spk_model_path) = &config.spk_model_path {
+        SPK_MODEL.set(
+            vosk::SpkModel::new(spk_model_path)?
         )
             .unwrap();
-    } else {
-        eprintln!("Model {:?} not found", config.model_path);
-        std::process::exit(-1);
     }
 
+    #[cfg(feature = "whisper")]
+    let whisper = match &config.whisper_model_path {
+        Some(path) => {
+            let ctx = whisper::Context::new(path)
+                .ok_or_else(|| format!("Could not load whisper model from {:?}", path))?;
+            Some(Arc::new(whisper::WhisperBackend::new(ctx, config.whisper_language.as_deref())?) as Arc<dyn SpeechBackend>)
+        },
+        None => None
+    };
+    #[cfg(not(feature = "whisper"))]
+    let whisper = None;
+
     let songbird_config = songbird::Config::default()
         .decode_mode(songbird::driver::DecodeMode::Decode);
 
     let db = BotDb::new(&config.db_path).await?;
+    db.create_table().await?;
+
+    // Member events need a privileged intent, so are only asked for
+    // if the bot is configured to
+    let mut intents = GatewayIntents::default();
+    if config.member_events {
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
 
     // Build our client.
-    let mut client = Client::builder(&*config.bot_token, GatewayIntents::default())
-        .event_handler(Handler::new(db))
+    let mut client = Client::builder(&*config.bot_token, intents)
+        .event_handler(Handler::new(db, models, pool, whisper))
         .application_id(config.application_id)
         .register_songbird_from_config(songbird_config)
         .await
@@ -127,16 +211,56 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
 struct Handler {
     // Use Mutex for now because SqliteConnection is not Sync
-    db: TokioMutex<BotDb>
+    db: TokioMutex<BotDb>,
+    models: Arc<ModelRegistry>,
+    pool: Arc<RecognitionPool>,
+    // Optional engine for message transcription only
+    whisper: Option<Arc<dyn SpeechBackend>>,
+    // Captioning session of each guild, for commands that talk to it
+    sessions: Mutex<HashMap<GuildId, Arc<voice_recv::VoiceReceive>>>,
+    profiles: Arc<ProfileCache>
 }
 
 impl Handler {
-    fn new(db: BotDb) -> Handler {
+    fn new(
+        db: BotDb,
+        models: Arc<ModelRegistry>,
+        pool: Arc<RecognitionPool>,
+        whisper: Option<Arc<dyn SpeechBackend>>
+    ) -> Handler
+    {
         Handler {
-            db: TokioMutex::new(db)
+            db: TokioMutex::new(db),
+            models,
+            pool,
+            whisper,
+            sessions: Default::default(),
+            profiles: Default::default()
         }
     }
 
+    /// A vosk backend running the model for `lang`
+    async fn vosk_backend(&self, lang: Option<&str>) -> Result<Arc<dyn SpeechBackend>, BotError<String>> {
+        let model = self.models.get(lang).await?;
+
+        Ok(Arc::new(vosk::VoskBackend::new(self.models.clone(), lang, model, SPK_MODEL.get())))
+    }
+
+    fn user_option(
+        options: &[ApplicationCommandInteractionDataOption],
+        name: &str
+    ) -> Option<UserId>
+    {
+        options
+            .iter()
+            .find(|o| o.name == name)
+            .and_then(|o| o.resolved.as_ref())
+            .and_then(|v| match v {
+                ApplicationCommandInteractionDataOptionValue::User(u, _) => Some(u.id),
+                _ => None
+            })
+    }
+
     async fn init_webhook(http: impl AsRef<Http>, chan: &GuildChannel) -> Result<Webhook, BotError<String>> {
         let hooks = chan.webhooks(&http).await?;
         let bot_id = http.as_ref().get_current_user().await?.id;
@@ -148,6 +272,46 @@ impl Handler {
         }
     }
 
+    /// Everywhere a captioning session in `chan` should post to
+    async fn caption_sinks(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        chan: &GuildChannel,
+        output: &str
+    ) -> Result<Vec<Box<dyn CaptionSink>>, BotError<String>>
+    {
+        let mut sinks: Vec<Box<dyn CaptionSink>> = Vec::new();
+
+        let message_sink = |destination| {
+            Box::new(
+                MessageSink::new(
+                    CaptionOutput::new(
+                        destination,
+                        ctx.cache.clone(),
+                        ctx.http.clone(),
+                        guild_id,
+                        self.profiles.clone())))
+        };
+
+        if output != "bot" {
+            sinks.push(message_sink(Destination::Webhook(Self::init_webhook(ctx, chan).await?)));
+        }
+
+        if output != "webhook" {
+            sinks.push(message_sink(Destination::Channel(chan.id)));
+        }
+
+        if let Some(dir) = &CONFIG.get().unwrap().transcript_dir {
+            match JsonlSink::create(dir.join(guild_id.to_string())) {
+                Ok(s) => sinks.push(Box::new(s)),
+                Err(e) => eprintln!("Could not start transcript in {:?}: {}", dir, e)
+            }
+        }
+
+        Ok(sinks)
+    }
+
     async fn handle_interaction(
         &self,
         ctx: &Context,
@@ -167,10 +331,33 @@ impl Handler {
                             .as_ref()
                             .ok_or(BotError::UserMessage("Expected channel object"))?;
 
+                        // Where captions are posted: "webhook", "bot" or
+                        // "both"
+                        let output = cmd
+                            .data
+                            .options
+                            .iter()
+                            .find(|o| o.name == "output")
+                            .and_then(|o| o.resolved.as_ref())
+                            .and_then(|v| match v {
+                                ApplicationCommandInteractionDataOptionValue::String(s) => Some(s.as_str()),
+                                _ => None
+                            })
+                            .unwrap_or("webhook");
+
                         if let ApplicationCommandInteractionDataOptionValue::Channel(ch) = opt {
                             let guild_id = cmd
                                 .guild_id
                                 .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                            // Loading the model may take longer than
+                            // Discord waits for a response
+                            cmd
+                                .create_interaction_response(ctx, |r| {
+                                    r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                                })
+                                .await?;
+
                             let db = self.db.lock().await;
                             let cfg_fut ={
                                 let db = &*db;
@@ -188,8 +375,13 @@ impl Handler {
                                 },
                                 Err(e) => return Err(e.into())
                             };
+                            let vocabulary = db.channel_vocabulary(ch.id).await?;
+                            let voiceprints = db.voiceprints(guild_id).await?;
+                            let endpointer = db.endpointer(guild_id).await?;
                             drop(db);
 
+                            let backend = self.vosk_backend(guild_config.lang.as_deref()).await?;
+
                             let manager = songbird::get(ctx).await
                                 .ok_or(BotError::<String>::Error(None))?;
 
@@ -213,13 +405,24 @@ impl Handler {
                                 let recv = voice_recv::ArcVoiceReceive(
                                     Arc::new(
                                         voice_recv::VoiceReceive::new(
-                                            MODEL.get().unwrap(),
-                                            ctx.cache.clone(),
-                                            ctx.http.clone(),
-                                            ch.id,
-                                            Self::init_webhook(ctx, &guild_ch).await?
+                                            backend,
+                                            self.pool.clone(),
+                                            self.caption_sinks(ctx, guild_id, &guild_ch, output).await?,
+                                            vocabulary,
+                                            endpointer,
+                                            CONFIG.get().unwrap().max_caption_secs
                                         )));
 
+                                for (user, microphone, xvector) in voiceprints {
+                                    recv.0.add_voiceprint(user, microphone, xvector);
+                                }
+
+                                self.sessions.lock().unwrap().insert(guild_id, recv.0.clone());
+
+                                let warm = recv.0.clone();
+                                tokio::spawn(async move { warm.warm_up().await });
+                                tokio::spawn(voice_recv::VoiceReceive::end_stalled_utterances(Arc::downgrade(&recv.0)));
+
                                 driver.add_global_event(
                                     CoreEvent::SpeakingStateUpdate.into(),
                                     recv.clone(),
@@ -246,14 +449,11 @@ impl Handler {
                                 );
                             }
 
-                            cmd
-                                .create_interaction_response(ctx, |r| {
-                                    r.kind(InteractionResponseType::ChannelMessageWithSource);
-                                    r.interaction_response_data(|d| {
-                                        d.content(format!("Captioning {}", ch.id.mention()))
-                                    })
-                                })
-                                .await?;
+                            cmd.edit_original_interaction_response(ctx, |r| {
+                                r.content(format!("Captioning {}", ch.id.mention()))
+                            })
+                                .await
+                                .or(Err(BotError::UserMessage("No response to edit")))?;
                         }
                     },
                     "set" => {
@@ -295,9 +495,461 @@ impl Handler {
                                         .await?;
                                 }
                             },
+                            "vocabulary" => {
+                                let opt = sub
+                                    .options
+                                    .iter()
+                                    .find(|o| o.name == "channel")
+                                    .ok_or(BotError::UserMessage("Expected channel option"))?
+                                    .resolved
+                                    .as_ref()
+                                    .ok_or(BotError::UserMessage("Expected channel object"))?;
+
+                                // Phrases are comma-separated; leaving them out
+                                // goes back to the open vocabulary
+                                let phrases: Option<Vec<String>> = sub
+                                    .options
+                                    .iter()
+                                    .find(|o| o.name == "phrases")
+                                    .and_then(|o| o.resolved.as_ref())
+                                    .and_then(|v| match v {
+                                        ApplicationCommandInteractionDataOptionValue::String(s) => Some(s),
+                                        _ => None
+                                    })
+                                    .map(|s| {
+                                        s
+                                            .split(',')
+                                            .map(|p| p.trim().to_lowercase())
+                                            .filter(|p| !p.is_empty())
+                                            .collect()
+                                    })
+                                    .filter(|p: &Vec<String>| !p.is_empty());
+
+                                if let ApplicationCommandInteractionDataOptionValue::Channel(ch) = opt {
+                                    let guild_id = cmd
+                                        .guild_id
+                                        .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+                                    let db = self.db.lock().await;
+
+                                    db.set_channel_vocabulary(guild_id, ch.id, phrases.as_deref()).await?;
+                                    drop(db);
+
+                                    let content = match &phrases {
+                                        Some(p) => format!(
+                                            "Captions in {} restricted to {} phrases (takes effect on the next /caption)",
+                                            ch.id.mention(),
+                                            p.len()),
+                                        None => format!("Captions in {} use the full vocabulary", ch.id.mention())
+                                    };
+
+                                    cmd
+                                        .create_interaction_response(ctx, |r| {
+                                            r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                            r.interaction_response_data(|d| {
+                                                d.content(content)
+                                            })
+                                        })
+                                        .await?;
+                                }
+                            },
+                            "language" => {
+                                let lang = match sub.options.get(0).and_then(|o| o.resolved.as_ref()) {
+                                    Some(ApplicationCommandInteractionDataOptionValue::String(l)) => Some(l.as_str()),
+                                    _ => None
+                                };
+
+                                if let Some(l) = lang {
+                                    if !self.models.languages().any(|known| known == l) {
+                                        return Err(BotError::UserMessage(format!("No model for language {:?}", l)));
+                                    }
+                                }
+
+                                let guild_id = cmd
+                                    .guild_id
+                                    .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                                self.db.lock().await.set_lang(guild_id, lang).await?;
+
+                                cmd
+                                    .create_interaction_response(ctx, |r| {
+                                        r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                        r.interaction_response_data(|d| {
+                                            d.content(match lang {
+                                                Some(l) => format!("Captioning language set to {} (takes effect on the next /caption)", l),
+                                                None => "Captioning language reset to the default".to_string()
+                                            })
+                                        })
+                                    })
+                                    .await?;
+                            },
+                            "engine" => {
+                                let engine = match sub.options.get(0).and_then(|o| o.resolved.as_ref()) {
+                                    Some(ApplicationCommandInteractionDataOptionValue::String(e)) => e.as_str(),
+                                    _ => return Err(BotError::UserMessage("Expected engine option").into())
+                                };
+
+                                if engine == "whisper" && self.whisper.is_none() {
+                                    return Err(BotError::UserMessage("Whisper is not available on this bot").into());
+                                }
+
+                                let guild_id = cmd
+                                    .guild_id
+                                    .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                                self.db.lock().await.set_transcription_engine(guild_id, engine).await?;
+
+                                cmd
+                                    .create_interaction_response(ctx, |r| {
+                                        r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                        r.interaction_response_data(|d| {
+                                            d.content(format!("Voice messages will be transcribed with {}", engine))
+                                        })
+                                    })
+                                    .await?;
+                            },
+                            "endpointing" => {
+                                let mut mode = None;
+                                let mut start_max = None;
+                                let mut end = None;
+                                let mut max = None;
+
+                                for opt in &sub.options {
+                                    match (&*opt.name, opt.resolved.as_ref()) {
+                                        ("mode", Some(ApplicationCommandInteractionDataOptionValue::String(m))) => {
+                                            mode = EndpointerMode::from_name(m);
+                                        },
+                                        ("start_max", Some(ApplicationCommandInteractionDataOptionValue::Number(n))) => {
+                                            start_max = Some(*n as f32);
+                                        },
+                                        ("end_silence", Some(ApplicationCommandInteractionDataOptionValue::Number(n))) => {
+                                            end = Some(*n as f32);
+                                        },
+                                        ("max_length", Some(ApplicationCommandInteractionDataOptionValue::Number(n))) => {
+                                            max = Some(*n as f32);
+                                        },
+                                        _ => {}
+                                    }
+                                }
+
+                                let mode = mode.ok_or(BotError::UserMessage("Expected mode option"))?;
+                                // libvosk only takes all three delays at once
+                                let delays = match (start_max, end, max) {
+                                    (Some(start_max), Some(end), Some(max)) => Some(EndpointerDelays { start_max, end, max }),
+                                    (None, None, None) => None,
+                                    _ => return Err(
+                                        BotError::UserMessage("Give all of start_max, end_silence and max_length, or none of them").into())
+                                };
+
+                                let guild_id = cmd
+                                    .guild_id
+                                    .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                                self.db.lock().await.set_endpointer(guild_id, &Endpointer { mode, delays }).await?;
+
+                                let content = match delays {
+                                    Some(d) => format!(
+                                        "Captions will end after {}s of silence, or {}s of speech",
+                                        d.end,
+                                        d.max),
+                                    None => format!("Captions will use {} endpointing", mode.name().replace('_', " "))
+                                };
+
+                                cmd
+                                    .create_interaction_response(ctx, |r| {
+                                        r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                        r.interaction_response_data(|d| {
+                                            d.content(format!("{}; this applies from the next /caption", content))
+                                        })
+                                    })
+                                    .await?;
+                            },
                             _ => {}
                         }
                     },
+                    "glossary" => {
+                        let sub = cmd
+                            .data
+                            .options
+                            .get(0)
+                            .ok_or(BotError::UserMessage("Expected subcommand"))?;
+                        let guild_id = cmd
+                            .guild_id
+                            .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                        // vosk vocabularies are lowercase
+                        let term = match sub.options.get(0).and_then(|o| o.resolved.as_ref()) {
+                            Some(ApplicationCommandInteractionDataOptionValue::String(t)) => {
+                                Some(t.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+                            },
+                            _ => None
+                        };
+
+                        let mut deferred = false;
+
+                        let content = match (&*sub.name, term) {
+                            ("add", Some(term)) if !term.is_empty() => {
+                                if self.db.lock().await.add_glossary_term(guild_id, &term).await? {
+                                    format!("Added {:?} to the glossary", term)
+                                } else {
+                                    format!("{:?} is already in the glossary", term)
+                                }
+                            },
+                            ("remove", Some(term)) => {
+                                if self.db.lock().await.remove_glossary_term(guild_id, &term).await? {
+                                    format!("Removed {:?} from the glossary", term)
+                                } else {
+                                    format!("{:?} is not in the glossary", term)
+                                }
+                            },
+                            ("check", _) => {
+                                // The model may need loading first
+                                cmd
+                                    .create_interaction_response(ctx, |r| {
+                                        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                                    })
+                                    .await?;
+                                deferred = true;
+
+                                let (lang, terms) = {
+                                    let db = self.db.lock().await;
+                                    let lang = db.guild_config(guild_id).await?.and_then(|c| c.lang);
+                                    (lang, db.glossary(guild_id).await?)
+                                };
+                                let model = self.models.get(lang.as_deref()).await?;
+
+                                let unknown: Vec<String> = terms
+                                    .iter()
+                                    .filter_map(|term| {
+                                        let words: Vec<&str> = term
+                                            .split_whitespace()
+                                            .filter(|w| model.find_word(w).is_none())
+                                            .collect();
+
+                                        if words.is_empty() {
+                                            None
+                                        } else {
+                                            Some(format!("- {} (unknown: {})", term, words.join(", ")))
+                                        }
+                                    })
+                                    .collect();
+
+                                if unknown.is_empty() {
+                                    format!("The model can recognize all {} glossary terms", terms.len())
+                                } else {
+                                    let mut content = format!(
+                                        "The model cannot recognize {} of {} glossary terms:",
+                                        unknown.len(),
+                                        terms.len());
+
+                                    // Stay under Discord's message length limit
+                                    for (i, line) in unknown.iter().enumerate() {
+                                        if content.len() + line.len() > 1900 {
+                                            content.push_str(&format!("\n...and {} more", unknown.len() - i));
+                                            break;
+                                        }
+                                        content.push('\n');
+                                        content.push_str(line);
+                                    }
+
+                                    content
+                                }
+                            },
+                            _ => return Err(BotError::UserMessage("Expected a term".to_string()))
+                        };
+
+                        if deferred {
+                            cmd.edit_original_interaction_response(ctx, |r| {
+                                r.content(content)
+                            })
+                                .await
+                                .or(Err(BotError::UserMessage("No response to edit")))?;
+                        } else {
+                            cmd
+                                .create_interaction_response(ctx, |r| {
+                                    r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                    r.interaction_response_data(|d| {
+                                        d.content(content)
+                                    })
+                                })
+                                .await?;
+                        }
+                    },
+                    "record" => {
+                        let sub = cmd
+                            .data
+                            .options
+                            .get(0)
+                            .ok_or(BotError::UserMessage("Expected subcommand"))?;
+                        let guild_id = cmd
+                            .guild_id
+                            .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+                        let dir = CONFIG
+                            .get()
+                            .unwrap()
+                            .recording_dir
+                            .as_ref()
+                            .ok_or(BotError::UserMessage("Recording is not set up on this bot"))?;
+
+                        let session = self
+                            .sessions
+                            .lock()
+                            .unwrap()
+                            .get(&guild_id)
+                            .cloned()
+                            .ok_or(BotError::UserMessage("Start captioning with /caption before recording"))?;
+
+                        let mut deferred = false;
+
+                        let content = match &*sub.name {
+                            "start" => {
+                                let recording = recording::Recording::start(
+                                    dir.join(guild_id.0.to_string()),
+                                    voice_recv::VOICE_SAMPLE_RATE)?;
+
+                                if !session.start_recording(recording) {
+                                    return Err(BotError::UserMessage("This call is already being recorded").into());
+                                }
+
+                                format!(
+                                    "{} started recording this call. Recordings are deleted after {} days.",
+                                    cmd.user.mention(),
+                                    CONFIG.get().unwrap().recording_retention_days)
+                            },
+                            "stop" => {
+                                let recording = session
+                                    .stop_recording()
+                                    .ok_or(BotError::UserMessage("This call is not being recorded"))?;
+
+                                // Mixing down a long recording takes a while
+                                cmd
+                                    .create_interaction_response(ctx, |r| {
+                                        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                                    })
+                                    .await?;
+                                deferred = true;
+
+                                let dir = recording.finish().await?;
+                                eprintln!("Saved recording to {:?}", dir);
+
+                                format!("{} stopped recording this call", cmd.user.mention())
+                            },
+                            _ => return Err(BotError::UserMessage("Unknown subcommand").into())
+                        };
+
+                        if deferred {
+                            cmd.edit_original_interaction_response(ctx, |r| {
+                                r.content(content)
+                            })
+                                .await
+                                .or(Err(BotError::UserMessage("No response to edit")))?;
+                        } else {
+                            cmd
+                                .create_interaction_response(ctx, |r| {
+                                    r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                    r.interaction_response_data(|d| d.content(content))
+                                })
+                                .await?;
+                        }
+                    },
+                    "reload_model" => {
+                        let owner = ctx.http.get_current_application_info().await?.owner.id;
+
+                        if cmd.user.id != owner {
+                            return Err(BotError::UserMessage("Only the bot owner can reload models").into());
+                        }
+
+                        let lang = match cmd.data.options.get(0).and_then(|o| o.resolved.as_ref()) {
+                            Some(ApplicationCommandInteractionDataOptionValue::String(l)) => Some(l.clone()),
+                            _ => None
+                        };
+                        let path = self.models.path_for(lang.as_deref()).to_owned();
+
+                        cmd
+                            .create_interaction_response(ctx, |r| {
+                                r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                r.interaction_response_data(|d| {
+                                    d.flags(InteractionApplicationCommandCallbackDataFlags::EPHEMERAL);
+                                    d.content(format!("Reloading {:?}...", path))
+                                })
+                            })
+                            .await?;
+
+                        let start = std::time::Instant::now();
+                        let content = match self.models.reload(lang.as_deref()).await {
+                            Ok(_) => format!("Reloaded {:?} in {:.1}s", path, start.elapsed().as_secs_f32()),
+                            Err(e) => format!("Reload failed, still using the old model: {}", e)
+                        };
+
+                        cmd.edit_original_interaction_response(ctx, |r| {
+                            r.content(content)
+                        })
+                            .await
+                            .or(Err(BotError::UserMessage("No response to edit")))?;
+                    },
+                    "enroll" => {
+                        let guild_id = cmd
+                            .guild_id
+                            .ok_or(BotError::UserMessage("This command can only be used in servers"))?;
+
+                        let member = Self::user_option(&cmd.data.options, "member").unwrap_or(cmd.user.id);
+                        let microphone = Self::user_option(&cmd.data.options, "microphone").unwrap_or(member);
+
+                        // Whoever is enrolled gets credited with what is
+                        // said on the microphone, so only moderators may
+                        // enroll anyone but themselves on their own
+                        let manages_guild = cmd
+                            .member
+                            .as_ref()
+                            .and_then(|m| m.permissions)
+                            .map_or(false, |p| p.manage_guild());
+
+                        if (member != cmd.user.id || microphone != cmd.user.id) && !manages_guild {
+                            return Err(BotError::UserMessage("Only members who can manage the server may enroll someone else or another microphone").into());
+                        }
+
+                        let session = self
+                            .sessions
+                            .lock()
+                            .unwrap()
+                            .get(&guild_id)
+                            .cloned()
+                            .ok_or(BotError::UserMessage("Start captioning with /caption before enrolling"))?;
+
+                        if !session.identifies_speakers() {
+                            return Err(BotError::UserMessage("Speaker identification is not set up on this bot").into());
+                        }
+
+                        let mut enrollment = session.enroll(microphone);
+
+                        cmd
+                            .create_interaction_response(ctx, |r| {
+                                r.kind(InteractionResponseType::ChannelMessageWithSource);
+                                r.interaction_response_data(|d| {
+                                    d.content(format!(
+                                        "Listening for {} on {}'s microphone; please speak for at least a few seconds",
+                                        member.mention(),
+                                        microphone.mention()))
+                                })
+                            })
+                            .await?;
+
+                        let content = match enrollment.wait(ENROLLMENT_TIMEOUT).await {
+                            Some(xvector) => {
+                                self.db.lock().await.set_voiceprint(guild_id, member, microphone, &xvector).await?;
+                                session.add_voiceprint(member, microphone, xvector);
+
+                                format!("Enrolled {}", member.mention())
+                            },
+                            None => format!("Enrollment of {} timed out", member.mention())
+                        };
+                        drop(enrollment);
+
+                        cmd.edit_original_interaction_response(ctx, |r| {
+                            r.content(content)
+                        })
+                            .await
+                            .or(Err(BotError::UserMessage("No response to edit")))?;
+                    },
                     "Caption Message" => {
                         use magnum::container::ogg::OpusSourceOgg;
 
@@ -327,25 +979,100 @@ impl Handler {
 
                         let audio_bytes = attach.download().await?;
 
-                        let mut source = OpusSourceOgg::new(std::io::Cursor::new(audio_bytes))
+                        let source = OpusSourceOgg::new(std::io::Cursor::new(audio_bytes))
                             .or(Err(BotError::UserMessage("Unsupported format (only OGG/Opus supported for now)")))?;
 
                         let sample_rate = source.metadata.sample_rate;
+                        let channels = source.metadata.channel_count.max(1) as usize;
+
+                        let (engine, lang) = match cmd.guild_id {
+                            Some(guild_id) => {
+                                let db = self.db.lock().await;
+                                let engine = db.transcription_engine(guild_id).await?;
+                                let lang = db.guild_config(guild_id).await?.and_then(|c| c.lang);
+                                (engine, lang)
+                            },
+                            None => (None, None)
+                        };
+                        let backend = match (engine.as_deref(), &self.whisper) {
+                            (Some("whisper"), Some(whisper)) => whisper.clone(),
+                            _ => self.vosk_backend(lang.as_deref()).await?
+                        };
 
-                        let mut rec = vosk::Recognizer::new(MODEL.get().unwrap(), sample_rate as f32);
+                        let options = SessionOptions {
+                            words: true,
+                            max_alternatives: TRANSCRIPT_ALTERNATIVES,
+                            ..Default::default()
+                        };
 
-                        let buf: Vec<i16> = source.map(|f| (f * 32767.0) as i16).collect();
+                        // Decoding and recognition are both CPU-heavy
+                        let (transcript, duration) = self.pool
+                            .run(move || -> Result<_, SpeechError> {
+                                let mut rec = backend.new_session(sample_rate as f32, &options)?;
 
-                        rec.accept_waveform_i16(&*buf);
+                                let buf: Vec<i16> = source.map(|f| (f * 32767.0) as i16).collect();
+                                let buf = audio::downmix(&buf, channels);
 
-                        let partial: std::ffi::CString = rec.partial_result_json().to_owned();
-                        let json: std::ffi::CString = rec.final_result_json().to_owned();
-                        let text = serde_json::from_slice::<vosk::SimpleResult>(json.to_bytes())
-                            .unwrap()
-                            .text;
+                                rec.accept_samples(&*buf);
+
+                                let duration = buf.len() as f32 / sample_rate as f32;
+                                Ok((rec.final_result()?, duration))
+                            })
+                            .await
+                            .and_then(|r| r)
+                            .map_err(|e| BotError::<String>::Error(Some(e)))?;
+
+                        if transcript.text.is_empty() {
+                            return Err(BotError::UserMessage("No speech recognized").into());
+                        }
+                        let best = &transcript;
+                        let alternatives = &transcript.alternatives;
+
+                        let mut content = format!(
+                            "Transcript of [voice message](<{}>) ({:.1}s):\n",
+                            msg.link(),
+                            duration);
+
+                        // Stay under Discord's message length limit,
+                        // cutting the transcript short if it is too long
+                        // on its own
+                        let text_limit = 1900 - content.len();
+                        if best.text.len() > text_limit {
+                            let end = best.text
+                                .char_indices()
+                                .map(|(i, c)| i + c.len_utf8())
+                                .take_while(|&end| end <= text_limit)
+                                .last()
+                                .unwrap_or(0);
+
+                            content.push_str(&best.text[..end]);
+                            content.push('…');
+                        } else {
+                            content.push_str(&best.text);
+                        }
+
+                        // Offer the runners-up in case the best reading
+                        // is wrong, if there is room
+                        let others: Vec<&str> = alternatives
+                            .iter()
+                            .skip(1)
+                            .map(|a| a.text.as_str())
+                            .filter(|t| !t.is_empty() && *t != best.text)
+                            .collect();
+
+                        if !others.is_empty() {
+                            let mut readings = "\n\nOther possible readings:".to_string();
+                            for (i, t) in others.iter().enumerate() {
+                                readings.push_str(&format!("\n{}. {}", i + 2, t));
+                            }
+
+                            if content.len() + readings.len() <= 1900 {
+                                content.push_str(&readings);
+                            }
+                        }
 
                         cmd.edit_original_interaction_response(ctx, |r| {
-                            r.content(format!("Transcript of [voice message](<{}>):\n{}", msg.link(), text))
+                            r.content(content)
                         })
                             .await
                             .or(Err(BotError::UserMessage("No response to edit")))?;
@@ -383,6 +1110,15 @@ impl EventHandler for Handler {
                             .description("The channel to join")
                             .kind(ApplicationCommandOptionType::Channel)
                             .required(true)
+                    }).create_option(|option| {
+                        option
+                            .name("output")
+                            .description("How captions are posted; through a webhook if left out")
+                            .kind(ApplicationCommandOptionType::String)
+                            .required(false)
+                            .add_string_choice("webhook, as each speaker", "webhook")
+                            .add_string_choice("bot messages", "bot")
+                            .add_string_choice("both", "both")
                     })
                 })
                 .create_application_command(|command| {
@@ -398,10 +1134,191 @@ impl EventHandler for Handler {
                                     .kind(ApplicationCommandOptionType::Channel)
                                     .required(true)
                             })
+                    }).create_option(|option| {
+                        option
+                            .name("vocabulary")
+                            .description("Restrict captions in a voice channel to a fixed list of phrases")
+                            .kind(ApplicationCommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o
+                                    .name("channel")
+                                    .description("The voice channel")
+                                    .kind(ApplicationCommandOptionType::Channel)
+                                    .required(true)
+                            })
+                            .create_sub_option(|o| {
+                                o
+                                    .name("phrases")
+                                    .description("Comma-separated phrases; leave out to allow all words")
+                                    .kind(ApplicationCommandOptionType::String)
+                                    .required(false)
+                            })
+                    }).create_option(|option| {
+                        option
+                            .name("language")
+                            .description("Set the captioning language")
+                            .kind(ApplicationCommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o
+                                    .name("code")
+                                    .description("Language code; leave out for the default model")
+                                    .kind(ApplicationCommandOptionType::String)
+                                    .required(false);
+                                for lang in self.models.languages() {
+                                    o.add_string_choice(lang, lang);
+                                }
+                                o
+                            })
+                    }).create_option(|option| {
+                        option
+                            .name("engine")
+                            .description("Choose the speech engine for voice message transcription")
+                            .kind(ApplicationCommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o
+                                    .name("engine")
+                                    .description("The engine")
+                                    .kind(ApplicationCommandOptionType::String)
+                                    .required(true)
+                                    .add_string_choice("vosk", "vosk");
+                                #[cfg(feature = "whisper")]
+                                o.add_string_choice("whisper", "whisper");
+                                o
+                            })
+                    }).create_option(|option| {
+                        option
+                            .name("endpointing")
+                            .description("Choose how long a pause ends a caption")
+                            .kind(ApplicationCommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o
+                                    .name("mode")
+                                    .description("How long a pause must be")
+                                    .kind(ApplicationCommandOptionType::String)
+                                    .required(true)
+                                    .add_string_choice("default", "default")
+                                    .add_string_choice("short", "short")
+                                    .add_string_choice("long", "long")
+                                    .add_string_choice("very long", "very_long")
+                            })
+                            .create_sub_option(|o| {
+                                o
+                                    .name("start_max")
+                                    .description("Seconds of silence allowed before speech; overrides the mode")
+                                    .kind(ApplicationCommandOptionType::Number)
+                                    .min_number_value(0.0)
+                                    .required(false)
+                            })
+                            .create_sub_option(|o| {
+                                o
+                                    .name("end_silence")
+                                    .description("Seconds of silence that end a caption; overrides the mode")
+                                    .kind(ApplicationCommandOptionType::Number)
+                                    .min_number_value(0.0)
+                                    .required(false)
+                            })
+                            .create_sub_option(|o| {
+                                o
+                                    .name("max_length")
+                                    .description("Longest a caption may get in seconds; overrides the mode")
+                                    .kind(ApplicationCommandOptionType::Number)
+                                    .min_number_value(0.0)
+                                    .required(false)
+                            })
                     })
                 })
                 .create_application_command(|command| {
-                    command.name("Transcribe Message").kind(CommandType::Message)
+                    command
+                        .name("glossary")
+                        .description("Manage this server's glossary of names and jargon")
+                        .create_option(|option| {
+                            option
+                                .name("add")
+                                .description("Add a term to the glossary")
+                                .kind(ApplicationCommandOptionType::SubCommand)
+                                .create_sub_option(|o| {
+                                    o
+                                        .name("term")
+                                        .description("The term")
+                                        .kind(ApplicationCommandOptionType::String)
+                                        .required(true)
+                                })
+                        })
+                        .create_option(|option| {
+                            option
+                                .name("remove")
+                                .description("Remove a term from the glossary")
+                                .kind(ApplicationCommandOptionType::SubCommand)
+                                .create_sub_option(|o| {
+                                    o
+                                        .name("term")
+                                        .description("The term")
+                                        .kind(ApplicationCommandOptionType::String)
+                                        .required(true)
+                                })
+                        })
+                        .create_option(|option| {
+                            option
+                                .name("check")
+                                .description("List glossary terms the speech model cannot recognize")
+                                .kind(ApplicationCommandOptionType::SubCommand)
+                        })
+                })
+                .create_application_command(|command| {
+                    command
+                        .name("record")
+                        .description("Record this call, with everyone in it told so")
+                        .default_member_permissions(Permissions::MANAGE_GUILD)
+                        .create_option(|option| {
+                            option
+                                .name("start")
+                                .description("Start recording")
+                                .kind(ApplicationCommandOptionType::SubCommand)
+                        })
+                        .create_option(|option| {
+                            option
+                                .name("stop")
+                                .description("Stop recording and save the recording")
+                                .kind(ApplicationCommandOptionType::SubCommand)
+                        })
+                })
+                .create_application_command(|command| {
+                    command
+                        .name("reload_model")
+                        .description("Reload a speech model from disk (bot owner only)")
+                        .create_option(|option| {
+                            option
+                                .name("language")
+                                .description("Language whose model to reload; leave out for the default model")
+                                .kind(ApplicationCommandOptionType::String)
+                                .required(false);
+                            for lang in self.models.languages() {
+                                option.add_string_choice(lang, lang);
+                            }
+                            option
+                        })
+                })
+                .create_application_command(|command| {
+                    command
+                        .name("enroll")
+                        .description("Record a voiceprint so captions from a shared microphone are credited correctly")
+                        .create_option(|option| {
+                            option
+                                .name("member")
+                                .description("The person who will speak (defaults to you)")
+                                .kind(ApplicationCommandOptionType::User)
+                                .required(false)
+                        })
+                        .create_option(|option| {
+                            option
+                                .name("microphone")
+                                .description("Whose microphone they will speak into (defaults to their own)")
+                                .kind(ApplicationCommandOptionType::User)
+                                .required(false)
+                        })
+                })
+                .create_application_command(|command| {
+                    command.name("Caption Message").kind(CommandType::Message)
                 })
         })
             .await;
@@ -409,23 +1326,47 @@ impl EventHandler for Handler {
         eprintln!("Commands: {:#?}", commands);
     }
 
+    async fn guild_member_addition(&self, _ctx: Context, member: Member) {
+        self.profiles.update(&member);
+    }
+
+    async fn guild_member_update(&self, _ctx: Context, _old: Option<Member>, member: Member) {
+        self.profiles.update(&member);
+    }
+
+    async fn guild_member_removal(&self, _ctx: Context, guild_id: GuildId, user: User, _member: Option<Member>) {
+        self.profiles.remove(guild_id, user.id);
+    }
+
+    async fn guild_delete(&self, _ctx: Context, guild: UnavailableGuild, _full: Option<Guild>) {
+        self.profiles.remove_guild(guild.id);
+    }
+
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Err(why1) = self.handle_interaction(&ctx, &interaction).await {
+            let message = match &why1 {
+                BotError::UserMessage(s) => s.clone(),
+                BotError::Error(_) => "Error running command".to_string()
+            };
             let mut response = CreateInteractionResponse::default();
             response.kind(InteractionResponseType::ChannelMessageWithSource);
             response.interaction_response_data(|d| {
                 d.flags(InteractionApplicationCommandCallbackDataFlags::EPHEMERAL);
-                d.content(
-                    match &why1 {
-                        BotError::UserMessage(s) => s.clone(),
-                        BotError::Error(_) => "Error running command".to_string()
-                    })
+                d.content(&message)
             });
             if let Err(why2) =
                 match interaction {
                     Interaction::ApplicationCommand(i) => {
-                        i.create_interaction_response(&ctx, |r| {*r = response; r})
-                            .await
+                        match i.create_interaction_response(&ctx, |r| {*r = response; r}).await {
+                            // Commands that deferred their response
+                            // have to edit it instead
+                            Err(_) => {
+                                i.edit_original_interaction_response(&ctx, |r| r.content(message))
+                                    .await
+                                    .map(|_| ())
+                            },
+                            ok => ok
+                        }
                     },
                     Interaction::MessageComponent(i) => {
                         i.create_interaction_response(&ctx, |r| {*r = response; r})
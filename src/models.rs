@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex as TokioMutex;
+
+use crate::vosk::{self, Recognizer, RecognizerConfig};
+
+// Most vosk models are trained on 16kHz audio
+const DEFAULT_SAMPLE_RATE: u32 = 16_000;
+
+// Reset recognizers kept per model and configuration, for when
+// several people stop talking at once
+const MAX_IDLE_RECOGNIZERS: usize = 4;
+
+#[derive(Debug)]
+pub enum ModelError {
+    Load(vosk::ModelError),
+    Join(tokio::task::JoinError)
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Load(e) => write!(f, "{}", e),
+            ModelError::Join(e) => write!(f, "Model loading task failed: {}", e)
+        }
+    }
+}
+
+impl Error for ModelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ModelError::Load(e) => Some(e),
+            ModelError::Join(e) => Some(e)
+        }
+    }
+}
+
+struct LoadedModel {
+    // Dropped after the model has been idle for a while. Sessions
+    // using the model hold their own `Arc`s, so it is only freed once
+    // they are done too; until then `weak` lets us hand it out again.
+    strong: Option<Arc<vosk::Model>>,
+    weak: Weak<vosk::Model>,
+    last_used: Instant,
+    // Reset recognizers for the model, ready for the next utterance.
+    // Creating one is slow enough to clip the first words spoken.
+    recognizers: HashMap<RecognizerConfig, Vec<Recognizer>>
+}
+
+/// Vosk models by language, loaded on first use and unloaded after
+/// sitting idle
+pub struct ModelRegistry {
+    default_path: PathBuf,
+    paths: HashMap<String, PathBuf>,
+    // Configured sample rates, for models whose own configuration does
+    // not give the right one
+    sample_rates: HashMap<PathBuf, u32>,
+    idle_timeout: Duration,
+    // Keyed by path, so languages sharing a model share one copy
+    loaded: Mutex<HashMap<PathBuf, LoadedModel>>,
+    // Held while loading a model so that it is not loaded twice at
+    // once, without holding up other models
+    loading: Mutex<HashMap<PathBuf, Arc<TokioMutex<()>>>>
+}
+
+impl ModelRegistry {
+    pub fn new(
+        default_path: PathBuf,
+        paths: HashMap<String, PathBuf>,
+        sample_rates: HashMap<PathBuf, u32>,
+        idle_timeout: Duration
+    ) -> ModelRegistry
+    {
+        ModelRegistry {
+            default_path,
+            paths,
+            sample_rates,
+            idle_timeout,
+            loaded: Default::default(),
+            loading: Default::default()
+        }
+    }
+
+    /// Configured language codes
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.paths.keys().map(|l| l.as_str())
+    }
+
+    /// All configured model directories
+    pub fn model_paths(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.default_path.as_path()).chain(self.paths.values().map(|p| p.as_path()))
+    }
+
+    /// The model directory for `lang`, falling back to the default
+    /// model for unknown or unset languages
+    pub fn path_for(&self, lang: Option<&str>) -> &Path {
+        lang
+            .and_then(|l| self.paths.get(l))
+            .unwrap_or(&self.default_path)
+    }
+
+    /// The sample rate the model in `path` expects its audio at
+    pub fn sample_rate(&self, path: &Path) -> u32 {
+        self.sample_rates
+            .get(path)
+            .copied()
+            .or_else(|| vosk::model_sample_rate(path))
+            .unwrap_or(DEFAULT_SAMPLE_RATE)
+    }
+
+    /// The current model for `path` if it is loaded
+    pub fn current(&self, path: &Path) -> Option<Arc<vosk::Model>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let entry = loaded.get_mut(path)?;
+        let model = entry.strong.clone().or_else(|| entry.weak.upgrade())?;
+
+        entry.strong = Some(model.clone());
+        entry.last_used = Instant::now();
+
+        Some(model)
+    }
+
+    fn loading_lock(&self, path: &Path) -> Arc<TokioMutex<()>> {
+        self.loading
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// The model for `lang`, loading it if necessary
+    pub async fn get(&self, lang: Option<&str>) -> Result<Arc<vosk::Model>, ModelError> {
+        let path = self.path_for(lang).to_owned();
+
+        if let Some(model) = self.current(&path) {
+            return Ok(model);
+        }
+
+        let loading = self.loading_lock(&path);
+        let _guard = loading.lock().await;
+
+        // Someone else may have loaded it while we waited
+        if let Some(model) = self.current(&path) {
+            return Ok(model);
+        }
+
+        self.load(path).await
+    }
+
+    /// Load the model for `lang` from disk again and swap it in. New
+    /// recognizers get the new model; existing ones keep the old one
+    /// alive until they are dropped. Idle recognizers for the old
+    /// model are freed.
+    pub async fn reload(&self, lang: Option<&str>) -> Result<Arc<vosk::Model>, ModelError> {
+        let path = self.path_for(lang).to_owned();
+        let loading = self.loading_lock(&path);
+        let _guard = loading.lock().await;
+
+        self.load(path).await
+    }
+
+    // Must be called with the loading lock for `path` held
+    async fn load(&self, path: PathBuf) -> Result<Arc<vosk::Model>, ModelError> {
+        eprintln!("Loading model {:?}", path);
+
+        let load_path = path.clone();
+        let model = tokio::task::spawn_blocking(move || vosk::Model::new(load_path))
+            .await
+            .map_err(ModelError::Join)?
+            .map_err(ModelError::Load)?;
+        let model = Arc::new(model);
+
+        // Freeing the old model's recognizers takes a while; do it
+        // outside the lock
+        let _replaced = self.loaded.lock().unwrap().insert(
+            path,
+            LoadedModel {
+                strong: Some(model.clone()),
+                weak: Arc::downgrade(&model),
+                last_used: Instant::now(),
+                recognizers: HashMap::new()
+            });
+
+        Ok(model)
+    }
+
+    /// Take an idle recognizer for `model`, if one with the same
+    /// configuration has been put back
+    pub fn take_recognizer(&self, path: &Path, model: &Arc<vosk::Model>, config: &RecognizerConfig) -> Option<Recognizer> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let entry = loaded.get_mut(path)?;
+
+        // Recognizers are dropped when their model is replaced, so
+        // this only fails for backends still holding an old model
+        if !entry.weak.ptr_eq(&Arc::downgrade(model)) {
+            return None;
+        }
+
+        entry.recognizers.get_mut(config)?.pop()
+    }
+
+    /// Keep a reset recognizer for reuse. It is dropped instead if its
+    /// model has since been reloaded or unloaded, or enough are idle
+    /// already.
+    pub fn put_recognizer(&self, path: &Path, config: RecognizerConfig, rec: Recognizer) {
+        // Freeing a recognizer takes a while; do it outside the lock
+        let _rejected = {
+            let mut loaded = self.loaded.lock().unwrap();
+
+            match loaded.get_mut(path) {
+                Some(entry) if entry.strong.is_some() && entry.weak.ptr_eq(&Arc::downgrade(rec.model())) => {
+                    let idle = entry.recognizers.entry(config).or_default();
+
+                    if idle.len() < MAX_IDLE_RECOGNIZERS {
+                        idle.push(rec);
+                        None
+                    } else {
+                        Some(rec)
+                    }
+                },
+                _ => Some(rec)
+            }
+        };
+    }
+
+    /// Drop our references to models that have not been asked for
+    /// within the idle timeout
+    pub fn unload_idle(&self) {
+        let mut unloaded = Vec::new();
+        let mut loaded = self.loaded.lock().unwrap();
+
+        for (path, entry) in loaded.iter_mut() {
+            if entry.strong.is_some() && entry.last_used.elapsed() >= self.idle_timeout {
+                eprintln!("Unloading idle model {:?}", path);
+                // Idle recognizers would otherwise keep the model alive
+                unloaded.push((entry.strong.take(), std::mem::take(&mut entry.recognizers)));
+            }
+        }
+
+        loaded.retain(|_, entry| entry.strong.is_some() || entry.weak.strong_count() > 0);
+
+        // Freeing models and recognizers takes a while; do it outside
+        // the lock
+        drop(loaded);
+        drop(unloaded);
+    }
+
+    /// Periodically unload idle models; runs forever
+    pub async fn run_unloader(self: Arc<Self>) {
+        let mut interval = tokio::time::interval((self.idle_timeout / 2).max(Duration::from_secs(1)));
+
+        loop {
+            interval.tick().await;
+            self.unload_idle();
+        }
+    }
+}
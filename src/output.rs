@@ -0,0 +1,463 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serenity::{
+    cache::Cache,
+    http::{client::Http, error::Error as HttpError},
+    model::{
+        id::{ChannelId, GuildId, MessageId, UserId},
+        webhook::Webhook
+    },
+    utils::MessageBuilder,
+    Error as SerenityError,
+    Result as SerenityResult,
+};
+use tokio::sync::mpsc;
+
+use crate::profiles::{ProfileCache, SpeakerProfile};
+
+// Longest message Discord accepts, in characters
+const MAX_MESSAGE_LEN: usize = 2000;
+
+// Tries at a request that fails for reasons that may pass
+const MAX_ATTEMPTS: u32 = 5;
+
+// Wait before the first retry, doubled after each
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+// Discord's JSON error code for a message that no longer exists
+const UNKNOWN_MESSAGE: isize = 10008;
+
+/// A caption sent to a `CaptionOutput`, whether or not it has been
+/// posted yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CaptionId(u64);
+
+// `keep` is false once a caption will not change again, so that it
+// can be forgotten once sent
+enum Op {
+    Post { id: CaptionId, user: Option<UserId>, content: String, keep: bool },
+    Edit { id: CaptionId, content: String, keep: bool },
+    Delete { id: CaptionId }
+}
+
+impl Op {
+    fn id(&self) -> CaptionId {
+        match self {
+            Op::Post { id, .. } | Op::Edit { id, .. } | Op::Delete { id } => *id
+        }
+    }
+}
+
+/// Where a `CaptionOutput` posts
+pub enum Destination {
+    /// Through a webhook, under each speaker's name and avatar
+    Webhook(Webhook),
+    /// As the bot itself, with each speaker's name in the message
+    Channel(ChannelId)
+}
+
+impl Destination {
+    fn channel_id(&self) -> Option<ChannelId> {
+        match self {
+            Destination::Webhook(w) => w.channel_id,
+            Destination::Channel(c) => Some(*c)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    // Worth trying again after a while
+    Transient,
+    // Only this request is at fault
+    Request,
+    // Nothing more can be posted to the destination
+    Destination
+}
+
+fn classify(e: &SerenityError) -> Failure {
+    match e {
+        SerenityError::Http(e) => match &**e {
+            HttpError::UnsuccessfulRequest(r) => {
+                let status = r.status_code.as_u16();
+
+                if status == 429 || status >= 500 {
+                    Failure::Transient
+                } else if status == 401 || status == 403 || (status == 404 && r.error.code != UNKNOWN_MESSAGE) {
+                    Failure::Destination
+                } else {
+                    Failure::Request
+                }
+            },
+            HttpError::Request(_) => Failure::Transient,
+            _ => Failure::Request
+        },
+        SerenityError::Io(_) => Failure::Transient,
+        _ => Failure::Request
+    }
+}
+
+/// Try `f` until it succeeds or fails for good
+async fn retry<T, F, Fut>(mut f: F) -> SerenityResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = SerenityResult<T>>
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Err(e) if attempt < MAX_ATTEMPTS && classify(&e) == Failure::Transient => {
+                eprintln!("Retrying caption request in {:?}: {}", backoff, e);
+
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            },
+            res => return res
+        }
+    }
+}
+
+/// Split `text` into messages short enough to post, between words
+/// where possible
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        let end = match rest.char_indices().nth(limit) {
+            Some((i, _)) => i,
+            None => {
+                pieces.push(rest.to_owned());
+                break;
+            }
+        };
+
+        let split = rest[..end]
+            .rfind(char::is_whitespace)
+            .filter(|&i| i > 0)
+            .unwrap_or(end);
+
+        pieces.push(rest[..split].trim_end().to_owned());
+        rest = rest[split..].trim_start();
+    }
+
+    pieces
+}
+
+/// Posts a session's captions to one destination, in order, without
+/// holding up whoever sends them. Requests wait out rate limits and
+/// are retried if they fail for reasons that may pass; edits that a
+/// later one replaces are skipped when it falls behind.
+pub struct CaptionOutput {
+    tx: mpsc::UnboundedSender<Op>,
+    next_id: AtomicU64
+}
+
+impl CaptionOutput {
+    /// Start posting to `destination`. Must be called from within the
+    /// runtime; the queue runs until the `CaptionOutput` is dropped and
+    /// the last captions are posted.
+    pub fn new(
+        destination: Destination,
+        cache: Arc<Cache>,
+        http: Arc<Http>,
+        guild: GuildId,
+        profiles: Arc<ProfileCache>
+    ) -> CaptionOutput
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let queue = OutputQueue {
+            destination,
+            cache,
+            http,
+            guild,
+            profiles,
+            posted: HashMap::new(),
+            failed: false
+        };
+        tokio::spawn(queue.run(rx));
+
+        CaptionOutput {
+            tx,
+            next_id: AtomicU64::new(0)
+        }
+    }
+
+    fn send(&self, op: Op) {
+        // The queue only stops once this is dropped
+        let _ = self.tx.send(op);
+    }
+
+    fn new_id(&self) -> CaptionId {
+        CaptionId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Post `content` as `user`, to be changed later
+    pub fn post(&self, user: Option<UserId>, content: String) -> CaptionId {
+        let id = self.new_id();
+
+        self.send(Op::Post { id, user, content, keep: true });
+
+        id
+    }
+
+    /// Post `content` as `user`, never to be changed
+    pub fn post_final(&self, user: Option<UserId>, content: String) {
+        let id = self.new_id();
+
+        self.send(Op::Post { id, user, content, keep: false });
+    }
+
+    pub fn edit(&self, id: CaptionId, content: String) {
+        self.send(Op::Edit { id, content, keep: true });
+    }
+
+    /// Change a caption for the last time
+    pub fn edit_final(&self, id: CaptionId, content: String) {
+        self.send(Op::Edit { id, content, keep: false });
+    }
+
+    pub fn delete(&self, id: CaptionId) {
+        self.send(Op::Delete { id });
+    }
+}
+
+struct Posted {
+    profile: SpeakerProfile,
+    // More than one if the caption was too long for one message
+    messages: Vec<MessageId>
+}
+
+struct OutputQueue {
+    destination: Destination,
+    cache: Arc<Cache>,
+    http: Arc<Http>,
+    guild: GuildId,
+    profiles: Arc<ProfileCache>,
+    posted: HashMap<CaptionId, Posted>,
+    // Set once the destination has stopped working
+    failed: bool
+}
+
+impl OutputQueue {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Op>) {
+        let mut queue = VecDeque::new();
+
+        loop {
+            if queue.is_empty() {
+                match rx.recv().await {
+                    Some(op) => queue.push_back(op),
+                    None => return
+                }
+            }
+
+            while let Ok(op) = rx.try_recv() {
+                queue.push_back(op);
+            }
+
+            let op = queue.pop_front().unwrap();
+
+            // Nothing sent now would be seen for long if a later edit
+            // or deletion of the same caption is waiting
+            let superseded = queue.iter().any(|o| {
+                o.id() == op.id() && match (&op, o) {
+                    (Op::Post { .. }, Op::Delete { .. }) => true,
+                    (Op::Edit { .. }, Op::Edit { .. } | Op::Delete { .. }) => true,
+                    _ => false
+                }
+            });
+
+            if superseded || self.failed {
+                continue;
+            }
+
+            if let Err(e) = self.perform(op).await {
+                match classify(&e) {
+                    Failure::Destination => {
+                        self.failed = true;
+                        self.report(&e).await;
+                    },
+                    _ => eprintln!("Could not post caption: {}", e)
+                }
+            }
+        }
+    }
+
+    /// Tell the channel that captions have stopped, since no one will
+    /// otherwise know why
+    async fn report(&self, e: &SerenityError) {
+        eprintln!("Captions can no longer be posted: {}", e);
+
+        let reason = match self.destination {
+            Destination::Webhook(_) => "the webhook they are posted through has stopped working",
+            Destination::Channel(_) => "the bot may no longer post or edit messages here"
+        };
+
+        if let Some(chan) = self.destination.channel_id() {
+            let res = chan.say(
+                &self.http,
+                format!("Captions can no longer be posted here, as {}. Use /caption again to restart captioning.", reason))
+                .await;
+
+            if let Err(e) = res {
+                eprintln!("Could not report webhook failure: {}", e);
+            }
+        }
+    }
+
+    async fn execute(&self, profile: &SpeakerProfile, content: &str) -> SerenityResult<Option<MessageId>> {
+        match &self.destination {
+            Destination::Webhook(webhook) => {
+                let m = retry(|| {
+                    let content = content.to_owned();
+
+                    webhook.execute(
+                        &self.http,
+                        true,
+                        |w| {
+                            w.content(content);
+                            w.allowed_mentions(|am| am.empty_parse());
+                            w.avatar_url(&profile.avatar);
+                            w.username(format!("[caption] {}", profile.name))
+                        })
+                })
+                    .await?;
+
+                Ok(m.map(|m| m.id))
+            },
+            Destination::Channel(chan) => {
+                let m = retry(|| {
+                    let content = content.to_owned();
+
+                    chan.send_message(&self.http, |m| {
+                        m.content(content).allowed_mentions(|am| am.empty_parse())
+                    })
+                })
+                    .await?;
+
+                Ok(Some(m.id))
+            }
+        }
+    }
+
+    async fn edit_message(&self, message: MessageId, content: &str) -> SerenityResult<()> {
+        match &self.destination {
+            Destination::Webhook(webhook) => {
+                retry(|| {
+                    let content = content.to_owned();
+
+                    webhook.edit_message(&self.http, message, |m| {
+                        m.content(content).allowed_mentions(|am| am.empty_parse())
+                    })
+                })
+                    .await?;
+            },
+            Destination::Channel(chan) => {
+                retry(|| {
+                    let content = content.to_owned();
+
+                    chan.edit_message(&self.http, message, |m| {
+                        m.content(content).allowed_mentions(|am| am.empty_parse())
+                    })
+                })
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, message: MessageId) -> SerenityResult<()> {
+        match &self.destination {
+            Destination::Webhook(webhook) => retry(|| webhook.delete_message(&self.http, message)).await,
+            Destination::Channel(chan) => retry(|| chan.delete_message(&self.http, message)).await
+        }
+    }
+
+    /// Make `posted` show `content`, reusing the messages it already
+    /// has and posting or deleting the difference
+    async fn update(&self, posted: &mut Posted, content: &str) -> SerenityResult<()> {
+        // Only a webhook can post under the speaker's name. Names are
+        // chosen by users, so are kept from being read as markdown.
+        let chunks = match self.destination {
+            Destination::Webhook(_) => split_message(content, MAX_MESSAGE_LEN),
+            Destination::Channel(_) => {
+                let content = MessageBuilder::new()
+                    .push_bold_safe(format!("{}:", posted.profile.name))
+                    .push(" ")
+                    .push(content)
+                    .build();
+
+                split_message(&content, MAX_MESSAGE_LEN)
+            }
+        };
+
+        for (&m, chunk) in posted.messages.iter().zip(&chunks) {
+            self.edit_message(m, chunk).await?;
+        }
+
+        for chunk in chunks.iter().skip(posted.messages.len()) {
+            if let Some(m) = self.execute(&posted.profile, chunk).await? {
+                posted.messages.push(m);
+            }
+        }
+
+        while posted.messages.len() > chunks.len() {
+            self.delete_message(*posted.messages.last().unwrap()).await?;
+            posted.messages.pop();
+        }
+
+        Ok(())
+    }
+
+    async fn perform(&mut self, op: Op) -> SerenityResult<()> {
+        match op {
+            Op::Post { id, user, content, keep } => {
+                let profile = match user {
+                    Some(u) => self.profiles.get(&self.cache, &self.http, self.guild, u).await,
+                    None => SpeakerProfile::unknown()
+                };
+                let mut posted = Posted { profile, messages: Vec::new() };
+
+                // Keep track of whatever was posted, even if not all of it
+                let res = self.update(&mut posted, &content).await;
+                if keep {
+                    self.posted.insert(id, posted);
+                }
+
+                res
+            },
+            Op::Edit { id, content, keep } => {
+                // Nothing to edit if it could not be posted
+                let mut posted = match self.posted.remove(&id) {
+                    Some(p) => p,
+                    None => return Ok(())
+                };
+
+                let res = self.update(&mut posted, &content).await;
+                if keep {
+                    self.posted.insert(id, posted);
+                }
+
+                res
+            },
+            Op::Delete { id } => {
+                if let Some(posted) = self.posted.remove(&id) {
+                    for m in posted.messages {
+                        self.delete_message(m).await?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serenity::{
+    cache::Cache,
+    http::client::Http,
+    model::{
+        guild::Member,
+        id::{GuildId, UserId},
+    },
+};
+
+// Longest a caption waits for Discord to say who someone is
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long a profile is trusted without hearing of any change, for
+// when member events are off or missed
+const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+// How long to wait before asking again about someone who could not be
+// looked up
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// What captions from someone are posted under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpeakerProfile {
+    pub name: String,
+    // Empty for the default avatar
+    pub avatar: String
+}
+
+impl SpeakerProfile {
+    pub fn unknown() -> SpeakerProfile {
+        SpeakerProfile {
+            name: "Unknown user".into(),
+            avatar: String::new()
+        }
+    }
+
+    fn from_member(m: &Member) -> SpeakerProfile {
+        SpeakerProfile {
+            name: m.display_name().into_owned(),
+            avatar: m.face()
+        }
+    }
+}
+
+struct Entry {
+    // `None` if the lookup failed
+    profile: Option<SpeakerProfile>,
+    updated: Instant
+}
+
+/// Display names and avatars of guild members, kept up to date from
+/// member events if the bot gets them, so that captioning seldom has
+/// to ask Discord. Members in serenity's cache, such as those in a
+/// voice channel, are always taken from there instead, as it is kept
+/// up to date.
+#[derive(Default)]
+pub struct ProfileCache {
+    guilds: Mutex<HashMap<GuildId, HashMap<UserId, Entry>>>
+}
+
+impl ProfileCache {
+    fn insert(&self, guild: GuildId, user: UserId, profile: Option<SpeakerProfile>) {
+        self.guilds
+            .lock()
+            .unwrap()
+            .entry(guild)
+            .or_default()
+            .insert(user, Entry { profile, updated: Instant::now() });
+    }
+
+    /// A member has joined or changed
+    pub fn update(&self, member: &Member) {
+        self.insert(member.guild_id, member.user.id, Some(SpeakerProfile::from_member(member)));
+    }
+
+    /// A member has left
+    pub fn remove(&self, guild: GuildId, user: UserId) {
+        if let Some(g) = self.guilds.lock().unwrap().get_mut(&guild) {
+            g.remove(&user);
+        }
+    }
+
+    /// Forget everything about a guild, such as when it is left
+    pub fn remove_guild(&self, guild: GuildId) {
+        self.guilds.lock().unwrap().remove(&guild);
+    }
+
+    /// The profile of `user` in `guild`. Asks Discord only if it is not
+    /// known already, and gives up quickly, falling back to an unknown
+    /// user.
+    pub async fn get(&self, cache: &Cache, http: &Http, guild: GuildId, user: UserId) -> SpeakerProfile {
+        if let Some(m) = cache.member(guild, user) {
+            return SpeakerProfile::from_member(&m);
+        }
+
+        let cached = {
+            self.guilds
+                .lock()
+                .unwrap()
+                .get(&guild)
+                .and_then(|g| g.get(&user))
+                .and_then(|e| {
+                    let max_age = if e.profile.is_some() { MAX_AGE } else { RETRY_AFTER };
+
+                    (e.updated.elapsed() < max_age).then(|| e.profile.clone())
+                })
+        };
+
+        if let Some(profile) = cached {
+            return profile.unwrap_or_else(SpeakerProfile::unknown);
+        }
+
+        let member = match tokio::time::timeout(FETCH_TIMEOUT, http.get_member(guild.0, user.0)).await {
+            Ok(Ok(m)) => Some(m),
+            Ok(Err(e)) => {
+                eprintln!("Could not look up member {} of {}: {}", user, guild, e);
+                None
+            },
+            Err(_) => {
+                eprintln!("Timed out looking up member {} of {}", user, guild);
+                None
+            }
+        };
+
+        let profile = member.as_ref().map(SpeakerProfile::from_member);
+        self.insert(guild, user, profile.clone());
+
+        profile.unwrap_or_else(SpeakerProfile::unknown)
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use tokio::sync::{mpsc, oneshot};
+
+// Length of the RIFF header written by `WavWriter`
+const WAV_HEADER_LEN: u64 = 44;
+
+// Most samples whose size fits in the header; about 12 hours at 48kHz
+const MAX_WAV_SAMPLES: u32 = (u32::MAX - WAV_HEADER_LEN as u32) / 2;
+
+/// Mono 16-bit PCM WAV file, written as it goes. The sizes in the
+/// header are only filled in by `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_u32::<LittleEndian>(16)?;
+        // PCM, 1 channel
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u32::<LittleEndian>(sample_rate)?;
+        // Byte rate and block alignment
+        file.write_u32::<LittleEndian>(sample_rate * 2)?;
+        file.write_u16::<LittleEndian>(2)?;
+        file.write_u16::<LittleEndian>(16)?;
+
+        file.write_all(b"data")?;
+        file.write_u32::<LittleEndian>(0)?;
+
+        Ok(WavWriter { file, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for &s in samples {
+            self.file.write_i16::<LittleEndian>(s)?;
+        }
+
+        self.samples += samples.len() as u32;
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_len = self.samples * 2;
+
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_u32::<LittleEndian>(data_len + WAV_HEADER_LEN as u32 - 8)?;
+        self.file.seek(SeekFrom::Start(WAV_HEADER_LEN - 4))?;
+        self.file.write_u32::<LittleEndian>(data_len)?;
+
+        self.file.flush()
+    }
+}
+
+// Samples mixed at a time, so that mixing long recordings does not
+// need them all in memory
+const MIX_CHUNK_LEN: usize = 1 << 16;
+
+/// Read up to `buf.len()` samples, returning how many there were
+fn read_samples(file: &mut impl Read, buf: &mut [i16]) -> io::Result<usize> {
+    let mut bytes = Vec::with_capacity(buf.len() * 2);
+    file.take(buf.len() as u64 * 2).read_to_end(&mut bytes)?;
+
+    let len = bytes.len() / 2;
+    LittleEndian::read_i16_into(&bytes[..len * 2], &mut buf[..len]);
+
+    Ok(len)
+}
+
+/// Mix tracks written by `WavWriter` into a new one at `out`
+fn mix(tracks: &[PathBuf], out: &Path, sample_rate: u32) -> io::Result<()> {
+    let mut tracks = tracks
+        .iter()
+        .map(|path| {
+            let mut file = BufReader::new(File::open(path)?);
+            file.seek(SeekFrom::Start(WAV_HEADER_LEN))?;
+            Ok(file)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut out = WavWriter::create(out, sample_rate)?;
+    let mut buf = vec![0; MIX_CHUNK_LEN];
+    let mut mix = vec![0i32; MIX_CHUNK_LEN];
+
+    loop {
+        mix.fill(0);
+        let mut len = 0;
+
+        for track in &mut tracks {
+            let n = read_samples(track, &mut buf)?;
+
+            for (m, &s) in mix.iter_mut().zip(&buf[..n]) {
+                *m += s as i32;
+            }
+
+            len = len.max(n);
+        }
+
+        if len == 0 {
+            return out.finish();
+        }
+
+        let chunk: Vec<i16> = mix[..len]
+            .iter()
+            .map(|&s| s.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .collect();
+        out.write(&chunk)?;
+    }
+}
+
+struct TrackAudio {
+    speaker: String,
+    // When it was received, in samples since the recording started
+    at: u32,
+    samples: Vec<i16>
+}
+
+/// Writes a recording's files, off the voice event handler
+struct RecordingWriter {
+    dir: PathBuf,
+    sample_rate: u32,
+    // `None` for tracks that are no longer written to, because they
+    // failed or are full
+    tracks: HashMap<String, Option<WavWriter>>,
+    // Tracks closed early, still to be mixed in
+    closed: Vec<PathBuf>
+}
+
+impl RecordingWriter {
+    fn track_path(&self, speaker: &str) -> PathBuf {
+        self.dir.join(format!("{}.wav", speaker))
+    }
+
+    fn write(&mut self, audio: TrackAudio) -> io::Result<()> {
+        let track = match self.tracks.get_mut(&audio.speaker) {
+            Some(Some(t)) => t,
+            Some(None) => return Ok(()),
+            None => {
+                let path = self.track_path(&audio.speaker);
+                let t = self.tracks.entry(audio.speaker.clone()).or_insert(None);
+                t.insert(WavWriter::create(path, self.sample_rate)?)
+            }
+        };
+
+        // Fill in silence for the time the speaker was not sending
+        // audio, allowing a packet's worth of lateness
+        let len = audio.samples.len() as u32;
+        let end = track.len() + len;
+        let silence = if audio.at > end + len { audio.at - end } else { 0 };
+
+        if track.len() as u64 + silence as u64 + len as u64 > MAX_WAV_SAMPLES as u64 {
+            eprintln!("Track {:?} of {:?} is full; recording no more of it", audio.speaker, self.dir);
+            self.close(&audio.speaker);
+            return Ok(());
+        }
+
+        track.write(&vec![0; silence as usize])?;
+        track.write(&audio.samples)
+    }
+
+    /// Stop writing to a track, keeping what it has so far
+    fn close(&mut self, speaker: &str) {
+        if let Some(track) = self.tracks.get_mut(speaker).and_then(Option::take) {
+            match track.finish() {
+                Ok(()) => self.closed.push(self.track_path(speaker)),
+                Err(e) => eprintln!("Could not finish track {:?} of {:?}: {}", speaker, self.dir, e)
+            }
+        }
+    }
+
+    /// Close all tracks and mix them down into `mix.wav`. Tracks that
+    /// cannot be finished are left out.
+    fn finish(mut self) -> io::Result<()> {
+        let speakers: Vec<String> = self.tracks.keys().cloned().collect();
+
+        for speaker in speakers {
+            self.close(&speaker);
+        }
+
+        mix(&self.closed, &self.dir.join("mix.wav"), self.sample_rate)
+    }
+
+    fn run(mut self, mut rx: mpsc::UnboundedReceiver<TrackAudio>, done: oneshot::Sender<io::Result<()>>) {
+        while let Some(audio) = rx.blocking_recv() {
+            let speaker = audio.speaker.clone();
+
+            if let Err(e) = self.write(audio) {
+                eprintln!("Could not record audio to {:?}: {}", self.dir, e);
+                self.close(&speaker);
+                // Give up on the track even if it was never created
+                self.tracks.insert(speaker, None);
+            }
+        }
+
+        let dir = self.dir.clone();
+        let res = self.finish();
+
+        // No one is waiting if the recording was dropped rather than
+        // finished
+        if let Err(Err(e)) = done.send(res) {
+            eprintln!("Could not save recording to {:?}: {}", dir, e);
+        }
+    }
+}
+
+/// A recording of a captioning session: one track per speaker, all
+/// starting at the start of the recording so that they line up, and
+/// a mixdown of them made when the recording is finished. Files are
+/// written on a blocking thread; dropping the recording finishes it
+/// there too.
+pub struct Recording {
+    dir: PathBuf,
+    sample_rate: u32,
+    start: Instant,
+    tx: mpsc::UnboundedSender<TrackAudio>,
+    done: oneshot::Receiver<io::Result<()>>
+}
+
+impl Recording {
+    /// Start recording into a new directory under `dir`. Must be called
+    /// from within the runtime.
+    pub fn start(dir: impl AsRef<Path>, sample_rate: u32) -> io::Result<Recording> {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let dir = dir.as_ref().join(started.to_string());
+
+        fs::create_dir_all(&dir)?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (done_tx, done) = oneshot::channel();
+
+        let writer = RecordingWriter {
+            dir: dir.clone(),
+            sample_rate,
+            tracks: HashMap::new(),
+            closed: Vec::new()
+        };
+        tokio::task::spawn_blocking(move || writer.run(rx, done_tx));
+
+        Ok(
+            Recording {
+                dir,
+                sample_rate,
+                start: Instant::now(),
+                tx,
+                done
+            }
+        )
+    }
+
+    /// Add audio that has just been received from `speaker`
+    pub fn write(&self, speaker: &str, samples: Vec<i16>) {
+        let at = (self.start.elapsed().as_secs_f64() * self.sample_rate as f64) as u32;
+
+        // The writer only stops once this is dropped
+        let _ = self.tx.send(TrackAudio { speaker: speaker.to_owned(), at, samples });
+    }
+
+    /// Close all tracks and mix them down into `mix.wav`, once
+    /// everything received so far is written
+    pub async fn finish(self) -> io::Result<PathBuf> {
+        let Recording { dir, tx, done, .. } = self;
+        drop(tx);
+
+        match done.await {
+            Ok(res) => res.map(|()| dir),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "recording writer stopped"))
+        }
+    }
+}
+
+/// Delete recordings under `dir` older than `retention`. Recordings
+/// are kept in a directory per guild, with one per session inside.
+/// Whatever cannot be looked at or deleted is skipped.
+pub fn remove_expired(dir: &Path, retention: Duration) -> io::Result<()> {
+    let now = SystemTime::now();
+
+    for guild in fs::read_dir(dir)? {
+        let guild = match guild.and_then(|g| Ok((g.file_type()?.is_dir(), g.path()))) {
+            Ok((true, path)) => path,
+            Ok((false, _)) => continue,
+            Err(e) => {
+                eprintln!("Could not clean up recordings in {:?}: {}", dir, e);
+                continue;
+            }
+        };
+
+        let sessions = match fs::read_dir(&guild) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Could not clean up recordings in {:?}: {}", guild, e);
+                continue;
+            }
+        };
+
+        for session in sessions {
+            if let Err(e) = session.and_then(|s| remove_if_expired(&s.path(), now, retention)) {
+                eprintln!("Could not clean up recordings in {:?}: {}", guild, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_if_expired(session: &Path, now: SystemTime, retention: Duration) -> io::Result<()> {
+    let modified = fs::metadata(session)?.modified()?;
+
+    if now.duration_since(modified).unwrap_or_default() >= retention {
+        eprintln!("Removing expired recording {:?}", session);
+        fs::remove_dir_all(session)?;
+    }
+
+    Ok(())
+}
+
+/// Periodically delete expired recordings; runs forever
+pub async fn run_cleanup(dir: PathBuf, retention: Duration) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let dir = dir.clone();
+        let res = tokio::task::spawn_blocking(move || remove_expired(&dir, retention)).await;
+
+        match res {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => eprintln!("Could not clean up recordings: {}", e),
+            Err(e) => eprintln!("Recording cleanup failed: {}", e)
+        }
+    }
+}
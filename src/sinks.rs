@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use serenity::model::id::UserId;
+use tokio::sync::mpsc;
+
+use crate::output::{CaptionOutput, CaptionId};
+use crate::voice_recv::SSRC;
+
+/// A caption, or the reading so far of one
+#[derive(Debug, Clone)]
+pub struct CaptionEvent {
+    /// Where the audio came from. Each stream has at most one caption
+    /// in progress, which its next final event finishes, or which is
+    /// abandoned when the utterance ends without one.
+    pub ssrc: SSRC,
+    /// Who is credited: the microphone's owner, or for final captions
+    /// the enrolled speaker recognized
+    pub speaker: Option<UserId>,
+    /// Empty if nothing was recognized after all
+    pub text: String,
+    /// When the words were spoken, in seconds since the utterance
+    /// started, if the backend says
+    pub start: Option<f32>,
+    pub end: Option<f32>,
+    pub time: SystemTime,
+    /// False for a reading that will be replaced
+    pub is_final: bool
+}
+
+/// Somewhere a session's captions go. Sinks are called from the voice
+/// event handler, so must hand anything slow off to another task.
+pub trait CaptionSink: Send + Sync {
+    fn send(&self, event: &CaptionEvent);
+
+    /// The utterance on `ssrc` is over; a caption of it still in
+    /// progress will never be finished
+    fn end(&self, _ssrc: SSRC) {}
+}
+
+// Webhooks and channels may only be posted to a few times a second,
+// so live captions are edited at most this often between them all,
+// however many people are speaking
+const LIVE_EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+
+struct LiveCaption {
+    caption: CaptionId,
+    // Who the message is posted as
+    user: Option<UserId>,
+    text: String
+}
+
+#[derive(Default)]
+struct Live {
+    captions: HashMap<SSRC, LiveCaption>,
+    // When any live caption was last edited
+    last_edit: Option<Instant>
+}
+
+/// Posts captions as Discord messages, showing each one as it is
+/// spoken in a message that is edited as it changes
+pub struct MessageSink {
+    output: CaptionOutput,
+    live: Mutex<Live>
+}
+
+impl MessageSink {
+    pub fn new(output: CaptionOutput) -> MessageSink {
+        MessageSink {
+            output,
+            live: Default::default()
+        }
+    }
+
+    fn show_partial(&self, event: &CaptionEvent) {
+        let mut live = self.live.lock().unwrap();
+        let throttled = live.last_edit.map_or(false, |t| t.elapsed() < LIVE_EDIT_INTERVAL);
+
+        match live.captions.get_mut(&event.ssrc) {
+            Some(l) if l.text == event.text || throttled => {},
+            Some(l) => {
+                self.output.edit(l.caption, format!("{} …", event.text));
+
+                l.text = event.text.clone();
+                live.last_edit = Some(Instant::now());
+            },
+            None => {
+                live.captions.insert(
+                    event.ssrc,
+                    LiveCaption {
+                        caption: self.output.post(event.speaker, format!("{} …", event.text)),
+                        user: event.speaker,
+                        text: event.text.clone()
+                    });
+            }
+        }
+    }
+
+    fn finish(&self, event: &CaptionEvent) {
+        let live = self.live.lock().unwrap().captions.remove(&event.ssrc);
+
+        match live {
+            // Messages cannot change author, so a live caption
+            // credited to the microphone's owner can only be edited if
+            // no one else turned out to be speaking
+            Some(l) if l.user == event.speaker && !event.text.is_empty() => {
+                self.output.edit_final(l.caption, event.text.clone());
+            },
+            _ => {
+                if let Some(l) = live {
+                    self.output.delete(l.caption);
+                }
+
+                if !event.text.is_empty() {
+                    self.output.post_final(event.speaker, event.text.clone());
+                }
+            }
+        }
+    }
+}
+
+impl CaptionSink for MessageSink {
+    fn send(&self, event: &CaptionEvent) {
+        if event.is_final {
+            self.finish(event);
+        } else {
+            self.show_partial(event);
+        }
+    }
+
+    fn end(&self, ssrc: SSRC) {
+        // Nothing was recognized after all
+        let live = self.live.lock().unwrap().captions.remove(&ssrc);
+
+        if let Some(l) = live {
+            self.output.delete(l.caption);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TranscriptLine<'a> {
+    // Unix time in seconds
+    time: f64,
+    speaker: Option<u64>,
+    text: &'a str,
+    start: Option<f32>,
+    end: Option<f32>
+}
+
+/// Writes final captions to a local file, one JSON object per line,
+/// on a blocking thread
+pub struct JsonlSink {
+    tx: mpsc::UnboundedSender<String>
+}
+
+impl JsonlSink {
+    /// Start a new transcript in `dir`, named for the time it started.
+    /// Must be called from within the runtime.
+    pub fn create(dir: impl AsRef<Path>) -> io::Result<JsonlSink> {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        fs::create_dir_all(&dir)?;
+
+        // Another transcript may have started in the same second
+        let mut n = 0;
+        let (path, file) = loop {
+            let name = match n {
+                0 => format!("{}.jsonl", started),
+                n => format!("{}-{}.jsonl", started, n)
+            };
+            let path = dir.as_ref().join(name);
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(f) => break (path, f),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e)
+            }
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || write_transcript(path, BufWriter::new(file), rx));
+
+        Ok(JsonlSink { tx })
+    }
+}
+
+fn write_transcript(path: PathBuf, mut file: BufWriter<File>, mut rx: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = rx.blocking_recv() {
+        let res = file
+            .write_all(line.as_bytes())
+            // Keep the file readable while the session goes on
+            .and_then(|()| file.flush());
+
+        if let Err(e) = res {
+            eprintln!("Could not write transcript to {:?}: {}", path, e);
+        }
+    }
+}
+
+impl CaptionSink for JsonlSink {
+    fn send(&self, event: &CaptionEvent) {
+        if !event.is_final || event.text.is_empty() {
+            return;
+        }
+
+        let line = TranscriptLine {
+            time: event.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            speaker: event.speaker.map(|u| u.0),
+            text: &event.text,
+            start: event.start,
+            end: event.end
+        };
+
+        let line = match serde_json::to_string(&line) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("Could not serialize transcript line: {}", e);
+                return;
+            }
+        };
+
+        // The writer only stops once this is dropped
+        let _ = self.tx.send(line + "\n");
+    }
+}
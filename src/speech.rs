@@ -0,0 +1,153 @@
+use std::error::Error;
+
+/// Errors from speech engines are opaque to the Discord side
+pub type SpeechError = Box<dyn Error + Send + Sync>;
+
+/// A speech recognition engine. Sessions hold whatever per-stream
+/// state the engine needs; the backend itself is shared.
+pub trait SpeechBackend: Send + Sync {
+    fn new_session(&self, sample_rate: f32, options: &SessionOptions) -> Result<Box<dyn SpeechSession>, SpeechError>;
+
+    /// Prepare for sessions with these settings so that the first one
+    /// starts quickly. This may block for a while.
+    fn warm_up(&self, _sample_rate: f32, _options: &SessionOptions) {}
+
+    /// The sample rate the engine works at, if it has one. Audio is
+    /// best resampled to it before sessions see it.
+    fn sample_rate(&self) -> Option<f32> {
+        None
+    }
+
+    /// Whether sessions created with `speaker_id` report voiceprints
+    fn identifies_speakers(&self) -> bool {
+        false
+    }
+}
+
+/// Recognition of one audio stream
+pub trait SpeechSession: Send {
+    /// Feed mono 16-bit samples. Returns true when the engine has
+    /// detected the end of a segment, whose text is then available
+    /// from `result`.
+    fn accept_samples(&mut self, samples: &[i16]) -> bool;
+
+    /// The reading so far of the segment in progress
+    fn partial(&mut self) -> Result<Partial, SpeechError>;
+
+    /// The segment ending at the last endpoint. The session keeps
+    /// going with the rest of the stream.
+    fn result(&mut self) -> Result<Transcript, SpeechError>;
+
+    /// Flush the remaining audio and return its transcript. The
+    /// session may be used again afterwards, starting a new segment.
+    fn final_result(&mut self) -> Result<Transcript, SpeechError>;
+
+    /// Drop any buffered audio and start over
+    fn reset(&mut self);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Report per-word timings
+    pub words: bool,
+    /// Number of candidate readings to report; 0 means just the best
+    pub max_alternatives: usize,
+    /// Only recognize these phrases
+    pub vocabulary: Option<Vec<String>>,
+    /// Report a voiceprint with each transcript, if the backend can
+    pub speaker_id: bool,
+    /// When to end a segment at a pause in speech
+    pub endpointer: Endpointer,
+    /// End a segment after this many seconds even without a pause
+    pub max_segment_secs: Option<f32>
+}
+
+/// How long a pause must be before the engine ends a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EndpointerMode {
+    #[default]
+    Default,
+    Short,
+    Long,
+    VeryLong
+}
+
+impl EndpointerMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            EndpointerMode::Default => "default",
+            EndpointerMode::Short => "short",
+            EndpointerMode::Long => "long",
+            EndpointerMode::VeryLong => "very_long"
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<EndpointerMode> {
+        match s {
+            "default" => Some(EndpointerMode::Default),
+            "short" => Some(EndpointerMode::Short),
+            "long" => Some(EndpointerMode::Long),
+            "very_long" => Some(EndpointerMode::VeryLong),
+            _ => None
+        }
+    }
+}
+
+/// Exact endpointing delays in seconds, overriding the mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndpointerDelays {
+    /// Longest silence allowed before any speech
+    pub start_max: f32,
+    /// Silence that ends a segment after speech
+    pub end: f32,
+    /// Longest a segment may get
+    pub max: f32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Endpointer {
+    pub mode: EndpointerMode,
+    pub delays: Option<EndpointerDelays>
+}
+
+/// A recognized word. `start` and `end` are in seconds from the start
+/// of the session.
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub word: String,
+    pub start: f32,
+    pub end: f32,
+    pub conf: Option<f32>
+}
+
+#[derive(Debug, Clone)]
+pub struct Alternative {
+    pub text: String,
+    pub words: Vec<Word>,
+    /// Engine-specific score; only comparable within one transcript
+    pub confidence: f32
+}
+
+/// A speaker embedding, computed over `frames` 10ms frames of audio
+#[derive(Debug, Clone)]
+pub struct Voiceprint {
+    pub xvector: Vec<f32>,
+    pub frames: u32
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    /// The best reading
+    pub text: String,
+    pub words: Vec<Word>,
+    /// All candidate readings, best first, if alternatives were
+    /// requested
+    pub alternatives: Vec<Alternative>,
+    pub voiceprint: Option<Voiceprint>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Partial {
+    pub text: String,
+    pub words: Vec<Word>
+}
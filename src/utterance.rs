@@ -0,0 +1,309 @@
+use std::time::{Duration, Instant};
+
+// Longest a speaker's audio may stop, without Discord saying they
+// stopped speaking, before their utterance is ended anyway
+const PACKET_GAP: Duration = Duration::from_secs(1);
+
+// How long after Discord says a speaker stopped their utterance is
+// kept open, for late packets or in case they carry on
+const TRAILING_SILENCE: Duration = Duration::from_millis(500);
+
+// Longest an utterance may take to finalize before it is given up on
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// No utterance in progress
+    Idle,
+    /// Voice is being heard
+    Speaking,
+    /// Discord says the speaker has stopped, but the utterance is kept
+    /// open until their audio does too
+    TrailingSilence { since: Instant },
+    /// The utterance is over and its last results are being collected
+    Finalizing { since: Instant }
+}
+
+/// Something that happened to a speaker's stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// Discord says the speaker started speaking. Both the gateway and
+    /// the packets themselves say so, so this often comes twice.
+    SpeakingStarted,
+    /// Discord says the speaker stopped speaking; also often twice
+    SpeakingStopped,
+    /// A packet of audio arrived
+    Packet,
+    /// Voice activity detection heard speech start
+    VoiceStarted,
+    /// Voice activity detection heard enough silence to end speech
+    VoiceEnded,
+    /// The utterance with this id has been finalized
+    Finalized(u64)
+}
+
+/// What the owner of the stream should do about an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Start recognizing a new utterance
+    Begin,
+    /// Finish recognizing the utterance in progress and caption it,
+    /// then send back `Input::Finalized` with its id
+    Finalize(u64),
+    /// Forget audio buffered from before a gap in the stream
+    Discontinuity
+}
+
+/// The utterances of one SSRC. Voice activity detection decides when
+/// they start; Discord's speaking updates and gaps in the audio can
+/// end them too, but only once each, however many times they repeat.
+#[derive(Debug, Clone)]
+pub struct Utterance {
+    state: State,
+    // Id of the current or most recent utterance
+    id: u64,
+    last_packet: Instant,
+    // Whether a gap has been dealt with since the last packet
+    discontinuous: bool
+}
+
+impl Utterance {
+    pub fn new(now: Instant) -> Utterance {
+        Utterance {
+            state: State::Idle,
+            id: 0,
+            last_packet: now,
+            discontinuous: true
+        }
+    }
+
+    fn finalize(&mut self, now: Instant) -> Option<Action> {
+        self.state = State::Finalizing { since: now };
+
+        Some(Action::Finalize(self.id))
+    }
+
+    pub fn handle(&mut self, input: Input, now: Instant) -> Option<Action> {
+        match (self.state, input) {
+            (_, Input::Packet) => {
+                self.last_packet = now;
+                self.discontinuous = false;
+                None
+            },
+            // A new utterance may start while the last is still being
+            // finalized
+            (State::Idle | State::Finalizing { .. }, Input::VoiceStarted) => {
+                self.id += 1;
+                self.state = State::Speaking;
+                Some(Action::Begin)
+            },
+            // If the client has been silent, any audio we have from it
+            // is from before the gap
+            (State::Idle | State::Finalizing { .. }, Input::SpeakingStarted) => {
+                if self.discontinuous || now.saturating_duration_since(self.last_packet) < TRAILING_SILENCE {
+                    None
+                } else {
+                    self.discontinuous = true;
+                    Some(Action::Discontinuity)
+                }
+            },
+            (State::Speaking, Input::SpeakingStopped) => {
+                self.state = State::TrailingSilence { since: now };
+                None
+            },
+            (State::TrailingSilence { .. }, Input::SpeakingStarted) => {
+                self.state = State::Speaking;
+                None
+            },
+            (State::Speaking | State::TrailingSilence { .. }, Input::VoiceEnded) => self.finalize(now),
+            (State::Finalizing { .. }, Input::Finalized(id)) if id == self.id => {
+                self.state = State::Idle;
+                None
+            },
+            _ => None
+        }
+    }
+
+    /// Check for timeouts; call this regularly
+    pub fn poll(&mut self, now: Instant) -> Option<Action> {
+        let quiet = now.saturating_duration_since(self.last_packet);
+
+        match self.state {
+            State::Speaking if quiet >= PACKET_GAP => self.finalize(now),
+            State::TrailingSilence { since } => {
+                if quiet >= PACKET_GAP || quiet.min(now.saturating_duration_since(since)) >= TRAILING_SILENCE {
+                    self.finalize(now)
+                } else {
+                    None
+                }
+            },
+            State::Finalizing { since } if now.saturating_duration_since(since) >= FINALIZE_TIMEOUT => {
+                eprintln!("Utterance {} took too long to finalize", self.id);
+                self.state = State::Idle;
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    // Speech that VAD picks up, with a packet every 20ms for `len`
+    fn speak(u: &mut Utterance, start: Instant, len: Duration) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let mut t = start;
+
+        actions.extend(u.handle(Input::SpeakingStarted, t));
+        actions.extend(u.handle(Input::Packet, t));
+        actions.extend(u.handle(Input::VoiceStarted, t));
+
+        while t < start + len {
+            t += ms(20);
+            actions.extend(u.handle(Input::Packet, t));
+            actions.extend(u.poll(t));
+        }
+
+        actions
+    }
+
+    #[test]
+    fn voice_activity_makes_one_utterance() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+
+        assert_eq!(speak(&mut u, t0, ms(2000)), vec![Action::Begin]);
+        assert_eq!(u.state, State::Speaking);
+
+        let t = t0 + ms(2500);
+        assert_eq!(u.handle(Input::VoiceEnded, t), Some(Action::Finalize(1)));
+        assert_eq!(u.handle(Input::Finalized(1), t), None);
+        assert_eq!(u.state, State::Idle);
+    }
+
+    #[test]
+    fn repeated_speaking_updates_finalize_once() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+
+        let t = t0 + ms(1000);
+        assert_eq!(u.handle(Input::SpeakingStopped, t), None);
+        assert_eq!(u.handle(Input::SpeakingStopped, t), None);
+        assert_eq!(u.state, State::TrailingSilence { since: t });
+
+        // Too soon to be sure they have stopped
+        assert_eq!(u.poll(t + ms(200)), None);
+        assert_eq!(u.poll(t + ms(500)), Some(Action::Finalize(1)));
+
+        // Neither the hints nor VAD catching up end it again
+        assert_eq!(u.handle(Input::SpeakingStopped, t + ms(600)), None);
+        assert_eq!(u.handle(Input::VoiceEnded, t + ms(600)), None);
+        assert_eq!(u.poll(t + ms(2000)), None);
+    }
+
+    #[test]
+    fn repeated_speaking_start_is_one_discontinuity() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        u.handle(Input::Packet, t0);
+
+        let t = t0 + ms(5000);
+        assert_eq!(u.handle(Input::SpeakingStarted, t), Some(Action::Discontinuity));
+        assert_eq!(u.handle(Input::SpeakingStarted, t), None);
+
+        // Only a real gap in the audio counts
+        u.handle(Input::Packet, t + ms(20));
+        assert_eq!(u.handle(Input::SpeakingStarted, t + ms(40)), None);
+        assert_eq!(u.handle(Input::SpeakingStarted, t + ms(1000)), Some(Action::Discontinuity));
+    }
+
+    #[test]
+    fn speaking_start_mid_utterance_does_not_reset() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(500));
+
+        assert_eq!(u.handle(Input::SpeakingStarted, t0 + ms(520)), None);
+        assert_eq!(u.state, State::Speaking);
+    }
+
+    #[test]
+    fn trailing_silence_waits_for_audio_to_stop() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+
+        let stop = t0 + ms(1000);
+        u.handle(Input::SpeakingStopped, stop);
+
+        // Late packets keep it open
+        let mut t = stop;
+        while t < stop + ms(800) {
+            t += ms(20);
+            u.handle(Input::Packet, t);
+            assert_eq!(u.poll(t), None);
+        }
+
+        assert_eq!(u.poll(t + ms(499)), None);
+        assert_eq!(u.poll(t + ms(500)), Some(Action::Finalize(1)));
+    }
+
+    #[test]
+    fn speaking_again_during_trailing_silence_continues() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+
+        u.handle(Input::SpeakingStopped, t0 + ms(1000));
+        assert_eq!(u.handle(Input::SpeakingStarted, t0 + ms(1100)), None);
+        assert_eq!(u.state, State::Speaking);
+        assert_eq!(u.poll(t0 + ms(1700)), None);
+    }
+
+    #[test]
+    fn stale_utterance_times_out() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+
+        // Audio stops with no speaking update
+        let last = t0 + ms(1000);
+        assert_eq!(u.poll(last + PACKET_GAP - ms(1)), None);
+        assert_eq!(u.poll(last + PACKET_GAP), Some(Action::Finalize(1)));
+    }
+
+    #[test]
+    fn late_finalized_does_not_end_next_utterance() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+        assert_eq!(u.handle(Input::VoiceEnded, t0 + ms(1000)), Some(Action::Finalize(1)));
+
+        // The next one starts before the first is finalized
+        assert_eq!(speak(&mut u, t0 + ms(1100), ms(500)), vec![Action::Begin]);
+        assert_eq!(u.handle(Input::Finalized(1), t0 + ms(1700)), None);
+        assert_eq!(u.state, State::Speaking);
+
+        assert_eq!(u.handle(Input::VoiceEnded, t0 + ms(2000)), Some(Action::Finalize(2)));
+        u.handle(Input::Finalized(2), t0 + ms(2100));
+        assert_eq!(u.state, State::Idle);
+    }
+
+    #[test]
+    fn stuck_finalization_is_given_up_on() {
+        let t0 = Instant::now();
+        let mut u = Utterance::new(t0);
+        speak(&mut u, t0, ms(1000));
+        u.handle(Input::VoiceEnded, t0 + ms(1000));
+
+        assert_eq!(u.poll(t0 + ms(1000) + FINALIZE_TIMEOUT), None);
+        assert_eq!(u.state, State::Idle);
+    }
+}
@@ -1,15 +1,10 @@
 use serenity::{
     async_trait,
-    model::{
-        id::{UserId, ChannelId},
-        webhook::Webhook
-    },
-    cache::Cache,
-    http::client::Http,
+    model::id::UserId,
 };
 use bimap::hash::BiHashMap;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
 use songbird::{
     events::{
         context_data::{SpeakingUpdateData, VoiceData},
@@ -19,177 +14,620 @@ use songbird::{
     },
     model::payload::{ClientDisconnect, Speaking},
 };
-use std::sync::{Arc, Mutex};
-use std::num::Wrapping;
-use discortp::rtp::Rtp;
-
-use serde_json::json;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use crate::vosk;
+use crate::speech::{SpeechBackend, SessionOptions, Transcript, Partial, Voiceprint, Endpointer};
+use crate::workers::{RecognitionPool, SpeakerWorker, WorkerEvent};
+use crate::audio::{self, Resampler, VoiceActivity, VoiceActivityDetector};
+use crate::recording::Recording;
+use crate::gaps::GapFiller;
+use crate::utterance::{Utterance, Input, Action};
+use crate::sinks::{CaptionSink, CaptionEvent};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SSRC(pub u32);
 
 pub struct VoiceReceive {
     // Map from audio SSRC to UserId
-    // LOCK ORDER: recognizers, ssrc_map
+    // LOCK ORDER: speakers, recording, ssrc_map
     ssrc_map: Mutex<BiHashMap<SSRC, UserId>>,
-    recognizers: Mutex<HashMap<SSRC, vosk::Recognizer>>,
-    model: &'static vosk::Model,
-    ctx: (Arc<Cache>, Arc<Http>),
-    chan: ChannelId,
-    webhook: Webhook,
+    speakers: Mutex<HashMap<SSRC, SpeakerStream>>,
+    backend: Arc<dyn SpeechBackend>,
+    // Rate the backend is fed at, and a fresh conditioner to get there
+    sample_rate: f32,
+    conditioner: AudioConditioner,
+    pool: Arc<RecognitionPool>,
+    // Everywhere captions go
+    sinks: Vec<Box<dyn CaptionSink>>,
+    // Options for new recognition sessions, including this channel's
+    // restricted vocabulary if any
+    session_options: SessionOptions,
+    // Enrolled x-vectors of speakers in this guild, with whose
+    // microphone each speaker shares
+    voiceprints: Mutex<Vec<(UserId, UserId, Vec<f32>)>>,
+    // Pending enrollments, keyed by the user whose microphone is
+    // being listened to
+    enrollments: Mutex<HashMap<UserId, oneshot::Sender<Vec<f32>>>>,
+    // Only while someone has asked for the call to be recorded
+    recording: Mutex<Option<Recording>>,
+}
+
+// Discord audio is decoded to 48kHz stereo
+pub const VOICE_SAMPLE_RATE: u32 = 48_000;
+const VOICE_CHANNELS: usize = 2;
+
+/// Turns decoded Discord audio into what the recognizer wants: mono,
+/// at the model's own sample rate. Holds the resampler's state, so
+/// each speaker needs their own.
+#[derive(Clone)]
+struct AudioConditioner {
+    // `None` if the backend takes Discord's rate as is
+    resampler: Option<Resampler>
+}
+
+// How often utterances are checked for timeouts
+const UTTERANCE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Audio kept from before speech is detected, so that the start of the
+// first word is not cut off
+const PREROLL_SECS: f32 = 0.3;
+
+/// The audio from one SSRC. Utterances are found by voice activity
+/// detection; Discord's speaking updates are only hints, since some
+/// clients never send the end of speech and open mics never stop.
+struct SpeakerStream {
+    utterance: Utterance,
+    gaps: GapFiller,
+    conditioner: AudioConditioner,
+    vad: VoiceActivityDetector,
+    // Conditioned audio from while not speaking, by packet
+    preroll: VecDeque<Vec<i16>>,
+    // Recognition of the utterance in progress, if any
+    worker: Option<SpeakerWorker>
+}
+
+impl SpeakerStream {
+    fn push_preroll(&mut self, samples: Vec<i16>, max_len: usize) {
+        self.preroll.push_back(samples);
+
+        while self.preroll.iter().map(|p| p.len()).sum::<usize>() > max_len {
+            self.preroll.pop_front();
+        }
+    }
+
+    /// Forget audio that came before a gap in the stream, keeping any
+    /// utterance in progress
+    fn discontinuity(&mut self) {
+        self.conditioner.reset();
+        self.preroll.clear();
+    }
+
+    /// Feed `input` to the utterance state machine and carry out what
+    /// it says, except for starting recognition. Returns the worker of
+    /// an utterance to finalize, with its id.
+    fn handle(&mut self, input: Input) -> Option<(u64, SpeakerWorker)> {
+        let action = self.utterance.handle(input, Instant::now());
+
+        self.act(action)
+    }
+
+    fn poll(&mut self) -> Option<(u64, SpeakerWorker)> {
+        let action = self.utterance.poll(Instant::now());
+
+        self.act(action)
+    }
+
+    fn act(&mut self, action: Option<Action>) -> Option<(u64, SpeakerWorker)> {
+        match action {
+            Some(Action::Discontinuity) => {
+                self.discontinuity();
+                None
+            },
+            Some(Action::Finalize(id)) => {
+                // VAD may not have heard the end, if something else
+                // ended the utterance
+                self.vad.reset();
+                self.worker.take().map(|w| (id, w))
+            },
+            Some(Action::Begin) | None => None
+        }
+    }
+}
+
+impl AudioConditioner {
+    fn new(sample_rate: u32) -> AudioConditioner {
+        AudioConditioner {
+            resampler: (sample_rate != VOICE_SAMPLE_RATE).then(|| Resampler::new(VOICE_SAMPLE_RATE, sample_rate))
+        }
+    }
+
+    fn process(&mut self, audio: &[i16]) -> Vec<i16> {
+        let mono = audio::downmix(audio, VOICE_CHANNELS);
+
+        match &mut self.resampler {
+            Some(r) => r.process(&mono),
+            None => mono
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(r) = &mut self.resampler {
+            r.reset();
+        }
+    }
+}
+
+// Largest cosine distance between x-vectors that still counts as the
+// same speaker
+const SPEAKER_MATCH_DISTANCE: f32 = 0.55;
+
+// Minimum number of 10ms frames an x-vector must be computed over to
+// be used for enrollment
+const MIN_ENROLLMENT_FRAMES: u32 = 300;
+
+// Minimum number of 10ms frames an x-vector must be computed over to
+// tell who is speaking; shorter segments are too easily mistaken
+const MIN_IDENTIFICATION_FRAMES: u32 = 150;
+
+/// A pending enrollment, cancelled when dropped
+pub struct Enrollment<'a> {
+    recv: &'a VoiceReceive,
+    microphone: UserId,
+    rx: Option<oneshot::Receiver<Vec<f32>>>
+}
+
+impl Enrollment<'_> {
+    /// The x-vector of the enrolled speaker, or `None` if they did not
+    /// say enough in time
+    pub async fn wait(&mut self, timeout: Duration) -> Option<Vec<f32>> {
+        let rx = self.rx.as_mut()?;
+
+        tokio::time::timeout(timeout, rx).await.ok()?.ok()
+    }
+}
+
+impl Drop for Enrollment<'_> {
+    fn drop(&mut self) {
+        self.rx = None;
+
+        // Leave it if a newer enrollment has replaced this one
+        let mut enrollments = self.recv.enrollments.lock().unwrap();
+
+        if enrollments.get(&self.microphone).map_or(false, |tx| tx.is_closed()) {
+            enrollments.remove(&self.microphone);
+        }
+    }
+}
+
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    1.0 - dot / (norm_a * norm_b)
 }
 
 impl VoiceReceive {
-    pub fn new(model: &'static vosk::Model, cache: Arc<Cache>, http: Arc<Http>, chan: ChannelId, webhook: Webhook) -> VoiceReceive {
+    pub fn new(backend: Arc<dyn SpeechBackend>, pool: Arc<RecognitionPool>, sinks: Vec<Box<dyn CaptionSink>>, vocabulary: Option<Vec<String>>, endpointer: Endpointer, max_segment_secs: f32) -> VoiceReceive {
+        let session_options = SessionOptions {
+            words: true,
+            vocabulary,
+            endpointer,
+            max_segment_secs: Some(max_segment_secs),
+            speaker_id: backend.identifies_speakers(),
+            ..Default::default()
+        };
+        let sample_rate = backend.sample_rate().unwrap_or(VOICE_SAMPLE_RATE as f32);
+
         VoiceReceive {
             ssrc_map: Default::default(),
-            recognizers: Default::default(),
-            model,
-            ctx: (cache, http),
-            chan,
-            webhook
+            speakers: Default::default(),
+            backend,
+            sample_rate,
+            conditioner: AudioConditioner::new(sample_rate as u32),
+            pool,
+            sinks,
+            session_options,
+            voiceprints: Default::default(),
+            enrollments: Default::default(),
+            recording: Default::default()
         }
     }
 
-    async fn begin_speaking(&self, ssrc: SSRC) {
-        eprintln!("Begin speaking");
-        let mut recognizers = self.recognizers.lock().unwrap();
+    /// Get the backend ready so the first utterance is not delayed
+    pub async fn warm_up(&self) {
+        let backend = self.backend.clone();
+        let options = self.session_options.clone();
+
+        let sample_rate = self.sample_rate;
 
-        recognizers.get_mut(&ssrc).map(|r| r.reset());
+        if let Err(e) = self.pool.run(move || backend.warm_up(sample_rate, &options)).await {
+            eprintln!("Could not warm up recognition: {}", e);
+        }
     }
 
-    async fn finish_speaking(&self, ssrc: SSRC) {
-        eprintln!("Finish speaking");
-        let r = {
-            self.recognizers.lock().unwrap().remove(&ssrc)
-        };
+    pub fn identifies_speakers(&self) -> bool {
+        self.session_options.speaker_id
+    }
+
+    /// Credit `user` with segments from `microphone` that sound like
+    /// `xvector`
+    pub fn add_voiceprint(&self, user: UserId, microphone: UserId, xvector: Vec<f32>) {
+        let mut voiceprints = self.voiceprints.lock().unwrap();
+
+        voiceprints.retain(|(u, _, _)| *u != user);
+        voiceprints.push((user, microphone, xvector));
+    }
 
+    /// Listen for the next long enough utterance from `microphone`,
+    /// to take its x-vector
+    pub fn enroll(&self, microphone: UserId) -> Enrollment<'_> {
+        let (tx, rx) = oneshot::channel();
 
-        if let Some(mut rec) = r {
-            let partial: std::ffi::CString = rec.partial_result_json().to_owned();
-            let json: std::ffi::CString = rec.final_result_json().to_owned();
-            let text = serde_json::from_slice::<vosk::SimpleResult>(json.to_bytes())
+        self.enrollments.lock().unwrap().insert(microphone, tx);
+
+        Enrollment {
+            recv: self,
+            microphone,
+            rx: Some(rx)
+        }
+    }
+
+    /// The speaker enrolled on `microphone` closest to `xvector`, if
+    /// any is close enough
+    fn identify_speaker(&self, microphone: UserId, xvector: &[f32]) -> Option<UserId> {
+        self.voiceprints
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, mic, _)| *mic == microphone)
+            .map(|(u, _, v)| (*u, cosine_distance(xvector, v)))
+            .filter(|(_, d)| *d <= SPEAKER_MATCH_DISTANCE)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(u, _)| u)
+    }
+
+    /// End utterances whose audio stopped without a speaking update,
+    /// or that Discord said had stopped. Runs until the `VoiceReceive`
+    /// is dropped.
+    pub async fn end_stalled_utterances(this: Weak<VoiceReceive>) {
+        let mut interval = tokio::time::interval(UTTERANCE_POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let this = match this.upgrade() {
+                Some(t) => t,
+                None => return
+            };
+
+            let stalled: Vec<(SSRC, (u64, SpeakerWorker))> = this.speakers
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .filter_map(|(ssrc, s)| s.poll().map(|w| (*ssrc, w)))
+                .collect();
+
+            for (ssrc, worker) in stalled {
+                this.finish_utterance(ssrc, worker);
+            }
+        }
+    }
+
+    fn new_stream(&self) -> SpeakerStream {
+        SpeakerStream {
+            utterance: Utterance::new(Instant::now()),
+            gaps: GapFiller::new(VOICE_CHANNELS),
+            conditioner: self.conditioner.clone(),
+            vad: VoiceActivityDetector::new(self.sample_rate),
+            preroll: VecDeque::new(),
+            worker: None
+        }
+    }
+
+    /// Feed a speaking update from Discord to `ssrc`'s utterances
+    fn speaking_update(self: &Arc<Self>, ssrc: SSRC, speaking: bool) {
+        let input = if speaking { Input::SpeakingStarted } else { Input::SpeakingStopped };
+
+        let w = {
+            self.speakers
+                .lock()
                 .unwrap()
-                .text;
+                .entry(ssrc)
+                .or_insert_with(|| self.new_stream())
+                .handle(input)
+        };
+
+        if let Some(worker) = w {
+            self.finish_utterance(ssrc, worker);
+        }
+    }
+
+    /// Caption the rest of an utterance, then let its stream know it
+    /// is done with. This happens in the background, so that neither
+    /// the speaker's next utterance nor anyone else's audio waits for
+    /// the recognizer to finish; the task doing it is returned.
+    fn finish_utterance(self: &Arc<Self>, ssrc: SSRC, (id, worker): (u64, SpeakerWorker)) -> JoinHandle<()> {
+        let this = self.clone();
 
-            let u = {
-                self.ssrc_map.lock().unwrap().get_by_left(&ssrc).copied()
+        tokio::spawn(async move {
+            if worker.dropped() > 0 {
+                eprintln!("Dropped {} packets from {:?} while recognition was overloaded", worker.dropped(), ssrc);
+            }
+
+            match worker.finish().await {
+                Ok(events) => this.handle_events(ssrc, events),
+                Err(e) => eprintln!("Dropping utterance: {}", e)
+            }
+
+            let idle = match this.speakers.lock().unwrap().get_mut(&ssrc) {
+                Some(s) => {
+                    s.handle(Input::Finalized(id));
+                    s.worker.is_none()
+                },
+                None => true
             };
 
-            let m: serenity::model::prelude::Member;
-            let n: std::borrow::Cow<'_, String>;
-
-            let (name, avatar) = match u {
-                Some(u) => {
-                    m = self
-                        .chan
-                        .to_channel((&self.ctx.0, &*self.ctx.1))
-                        .await
-                        .unwrap()
-                        .guild()
-                        .unwrap()
-                        .guild_id
-                        .member((&self.ctx.0, &*self.ctx.1), u)
-                        .await
-                        .unwrap();
-                    n = m.display_name();
-                    (n.as_str(), m.face())
+            // Unless the speaker's next utterance has already taken over
+            // the caption in progress
+            if idle {
+                for sink in &this.sinks {
+                    sink.end(ssrc);
+                }
+            }
+        })
+    }
+
+    /// Learn who is behind `ssrc`. Discord may not say until after
+    /// their audio has started, or at all; captions before then are
+    /// credited to an unknown user.
+    fn bind_user(&self, ssrc: SSRC, user: UserId) {
+        self.ssrc_map.lock().unwrap().insert(ssrc, user);
+    }
+
+    fn handle_events(&self, ssrc: SSRC, events: Vec<WorkerEvent>) {
+        for e in events {
+            match e {
+                WorkerEvent::Partial(p) => self.show_partial(ssrc, p),
+                WorkerEvent::Segment(t) => self.caption(ssrc, t)
+            }
+        }
+    }
+
+    fn send(&self, event: CaptionEvent) {
+        for sink in &self.sinks {
+            sink.send(&event);
+        }
+    }
+
+    /// Show the reading so far of the segment in progress
+    fn show_partial(&self, ssrc: SSRC, partial: Partial) {
+        let Partial { text, words } = partial;
+
+        let speaker = self.ssrc_map.lock().unwrap().get_by_left(&ssrc).copied();
+
+        self.send(CaptionEvent {
+            ssrc,
+            speaker,
+            text,
+            start: words.first().map(|w| w.start),
+            end: words.last().map(|w| w.end),
+            time: SystemTime::now(),
+            is_final: false
+        });
+    }
+
+    /// Caption one segment of an utterance, replacing the reading so
+    /// far if there is one
+    fn caption(&self, ssrc: SSRC, transcript: Transcript) {
+        let Transcript { text, words, voiceprint, .. } = transcript;
+
+        let mut u = {
+            self.ssrc_map.lock().unwrap().get_by_left(&ssrc).copied()
+        };
+
+        if let Some(Voiceprint { xvector, frames }) = voiceprint {
+            // Hand the voiceprint to a pending enrollment for this
+            // microphone, or credit the closest speaker enrolled on it
+            let enrollment = match u {
+                Some(mic) if frames >= MIN_ENROLLMENT_FRAMES => {
+                    self.enrollments.lock().unwrap().remove(&mic)
                 },
-                None => ("Unknown user", String::new())
+                _ => None
             };
 
-            if !text.is_empty() {
-                let map = json!({"name": "CaptionBot"});
-
-                self.webhook.execute(
-                    &self.ctx.1,
-                    false,
-                    |w| {
-                        w.content(text);
-                        w.avatar_url(avatar);
-                        w.username(format!("[caption] {}", name))
-                    })
-                    .await
-                    .unwrap();
+            // If the enrollment was given up on, this is ordinary
+            // speech after all
+            let xvector = match enrollment {
+                Some(tx) => tx.send(xvector).err(),
+                None => Some(xvector)
+            };
+
+            let speaker = match (u, xvector) {
+                (Some(mic), Some(x)) if frames >= MIN_IDENTIFICATION_FRAMES => self.identify_speaker(mic, &x),
+                _ => None
+            };
+
+            if let Some(speaker) = speaker {
+                u = Some(speaker);
             }
         }
+
+        self.send(CaptionEvent {
+            ssrc,
+            speaker: u,
+            text,
+            start: words.first().map(|w| w.start),
+            end: words.last().map(|w| w.end),
+            time: SystemTime::now(),
+            is_final: true
+        });
+    }
+
+    /// Start recording the call. Returns false if it is already being
+    /// recorded.
+    pub fn start_recording(&self, recording: Recording) -> bool {
+        let mut current = self.recording.lock().unwrap();
+
+        if current.is_some() {
+            return false;
+        }
+
+        *current = Some(recording);
+        true
     }
 
-    async fn process_audio(&self, data: VoiceData<'_>) {
-        use std::fs::OpenOptions;
-        use byteorder::WriteBytesExt;
-        
-        let ssrc = SSRC(u32::from_be(data.packet.ssrc));
-        let mut recognizers = self.recognizers.lock().unwrap();
-        let rec = recognizers.entry(ssrc).or_insert_with(|| vosk::Recognizer::new(&self.model, 48_000.0));
+    pub fn stop_recording(&self) -> Option<Recording> {
+        self.recording.lock().unwrap().take()
+    }
+
+    fn record(&self, ssrc: SSRC, audio: &[i16]) {
+        let recording = self.recording.lock().unwrap();
+
+        if let Some(r) = &*recording {
+            let name = match self.ssrc_map.lock().unwrap().get_by_left(&ssrc) {
+                Some(u) => u.0.to_string(),
+                None => format!("ssrc-{}", ssrc.0)
+            };
+
+            r.write(&name, audio::downmix(audio, VOICE_CHANNELS));
+        }
+    }
+
+    /// Feed one packet's worth of audio through VAD and recognition.
+    /// Returns the worker of an utterance that has just ended.
+    fn process_frame(&self, speaker: &mut SpeakerStream, frame: &[i16]) -> Option<(u64, SpeakerWorker)> {
+        let samples = speaker.conditioner.process(frame);
+        let action = match speaker.vad.process(&samples) {
+            Some(VoiceActivity::Start) => speaker.utterance.handle(Input::VoiceStarted, Instant::now()),
+            Some(VoiceActivity::End) => speaker.utterance.handle(Input::VoiceEnded, Instant::now()),
+            None => None
+        };
 
-        let mono_data: Vec<i16> = data.audio.as_ref().unwrap().chunks_exact(2).map(|c| c[0]/2 + c[1]/2).collect();
+        if action == Some(Action::Begin) {
+            let worker = self.pool.spawn_worker(self.backend.clone(), self.sample_rate, self.session_options.clone());
 
-        let mut f = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .write(true)
-            .open("capture.raw")
-            .unwrap();
+            for p in speaker.preroll.drain(..) {
+                worker.push_audio(p);
+            }
 
-        for &s in mono_data.iter() {
-            f.write_i16::<byteorder::NativeEndian>(s).unwrap();
+            speaker.worker = Some(worker);
         }
 
-        if rec.accept_waveform_i16(&*mono_data) {
-            // eprintln!("{:?}", rec.partial_result_json());
-            // TODO: edit message with partial results
-        } else {
-            // eprintln!("{:?}", rec.partial_result_json());
-            // TODO
+        match &mut speaker.worker {
+            Some(worker) => {
+                worker.push_audio(samples);
+            },
+            None => {
+                speaker.push_preroll(samples, (PREROLL_SECS * self.sample_rate) as usize);
+            }
+        }
+
+        speaker.act(action)
+    }
+
+    fn process_audio(self: &Arc<Self>, data: VoiceData<'_>) {
+        let ssrc = SSRC(u32::from_be(data.packet.ssrc));
+
+        let (events, ended) = {
+            let mut speakers = self.speakers.lock().unwrap();
+            let speaker = speakers.entry(ssrc).or_insert_with(|| self.new_stream());
+            speaker.handle(Input::Packet);
+
+            // Songbird gives no audio for packets it could not decode,
+            // and an empty one for packets that came too late
+            let audio = data.audio.as_deref().filter(|a| !a.is_empty());
+            let frame = speaker.gaps.push(
+                data.packet.sequence.into(),
+                data.packet.timestamp.into(),
+                audio);
+
+            let mut events = Vec::new();
+            let mut ended = Vec::new();
+
+            if let Some(frame) = frame {
+                self.record(ssrc, &frame);
+
+                ended.extend(self.process_frame(speaker, &frame));
+
+                if let Some(w) = &mut speaker.worker {
+                    events.extend(w.events());
+                }
+            }
+
+            (events, ended)
+        };
+
+        // Show results as they come, without waiting for the speaker
+        // to stop altogether
+        self.handle_events(ssrc, events);
+
+        for worker in ended {
+            self.finish_utterance(ssrc, worker);
         }
     }
 }
 
+#[derive(Clone)]
+pub struct ArcVoiceReceive(pub Arc<VoiceReceive>);
+
 #[async_trait]
-impl EventHandler for VoiceReceive {
+impl EventHandler for ArcVoiceReceive {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let this = &self.0;
+
         match *ctx {
             EventContext::SpeakingStateUpdate(Speaking { speaking, ssrc, user_id, .. }) => {
                 let ssrc = SSRC(u32::from_be(ssrc));
-                let user_id = UserId(user_id.unwrap().0);
 
-                {
-                    self.ssrc_map.lock().unwrap().insert(ssrc, user_id);
+                if let Some(u) = user_id {
+                    this.bind_user(ssrc, UserId(u.0));
                 }
 
-                if speaking.is_empty() {
-                    self.finish_speaking(ssrc).await;
-                } else {
-                    self.begin_speaking(ssrc).await;
-                }
+                this.speaking_update(ssrc, !speaking.is_empty());
             },
             EventContext::SpeakingUpdate(SpeakingUpdateData { speaking, ssrc, .. }) => {
                 let ssrc = SSRC(u32::from_be(ssrc));
 
-                if speaking {
-                    self.begin_speaking(ssrc).await;
-                } else {
-                    self.finish_speaking(ssrc).await;
-                }
+                this.speaking_update(ssrc, speaking);
             },
             EventContext::ClientDisconnect(ClientDisconnect { user_id }) => {
                 let user_id = UserId(user_id.0);
 
                 let res = {
-                    self.ssrc_map.lock().unwrap().get_by_right(&user_id).copied()
+                    this.ssrc_map.lock().unwrap().get_by_right(&user_id).copied()
                 };
                 if let Some(ssrc) = res
                 {
-                    self.finish_speaking(ssrc).await;
+                    // They cannot be heard any more, so whatever they
+                    // were saying is over
+                    let w = {
+                        this.speakers.lock().unwrap().remove(&ssrc).and_then(|mut s| s.handle(Input::VoiceEnded))
+                    };
+
+                    let finished = w.map(|worker| this.finish_utterance(ssrc, worker));
+                    let this = this.clone();
 
-                    self.ssrc_map.lock().unwrap().remove_by_right(&user_id);
+                    tokio::spawn(async move {
+                        // Their last words are still credited to them
+                        if let Some(f) = finished {
+                            let _ = f.await;
+                        }
+
+                        let mut ssrc_map = this.ssrc_map.lock().unwrap();
+
+                        // Unless the SSRC has been given to someone else
+                        // meanwhile
+                        if ssrc_map.get_by_left(&ssrc) == Some(&user_id) {
+                            ssrc_map.remove_by_left(&ssrc);
+                        }
+                    });
                 }
             },
             EventContext::VoicePacket(ref p) => {
-                self.process_audio(p.clone()).await;
+                this.process_audio(p.clone());
             },
             _ => {}
         }
@@ -197,13 +635,3 @@ impl EventHandler for VoiceReceive {
         None
     }
 }
-
-#[derive(Clone)]
-pub struct ArcVoiceReceive(pub Arc<VoiceReceive>);
-
-#[async_trait]
-impl EventHandler for ArcVoiceReceive {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        self.0.act(ctx).await
-    }
-}
@@ -1,6 +1,10 @@
 pub mod sys {
-    use std::os::raw::{c_char, c_int, c_short};
-    use std::marker::PhantomData;
+    use std::os::raw::{c_char, c_int, c_short, c_void};
+    use std::ffi::{CStr, CString};
+    use std::path::{Path, PathBuf};
+    use std::error::Error;
+    use std::fmt;
+    use once_cell::sync::OnceCell;
 
     #[repr(C)]
     pub struct VoskModel {
@@ -10,32 +14,336 @@ pub mod sys {
     pub struct VoskRecognizer {
         _data: [u8; 0]
     }
+    #[repr(C)]
+    pub struct VoskSpkModel {
+        _data: [u8; 0]
+    }
+
+    // VoskEndpointerMode
+    pub const VOSK_EP_ANSWER_DEFAULT: c_int = 0;
+    pub const VOSK_EP_ANSWER_SHORT: c_int = 1;
+    pub const VOSK_EP_ANSWER_LONG: c_int = 2;
+    pub const VOSK_EP_ANSWER_VERY_LONG: c_int = 3;
+
+    static LIBRARY: OnceCell<Library> = OnceCell::new();
+
+    #[derive(Debug)]
+    pub enum LoadError {
+        /// dlopen failed; `reason` is from dlerror, and usually names
+        /// the missing file (which may be a dependency of libvosk)
+        Open { path: PathBuf, reason: String },
+        /// The library is too old (or not libvosk at all)
+        MissingSymbols { path: PathBuf, symbols: Vec<&'static str> },
+        AlreadyLoaded
+    }
+
+    impl fmt::Display for LoadError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                LoadError::Open { path, reason } =>
+                    write!(f, "Could not load libvosk from {:?}: {}", path, reason),
+                LoadError::MissingSymbols { path, symbols } =>
+                    write!(
+                        f,
+                        "libvosk at {:?} is missing {}; it is probably older than this bot supports",
+                        path,
+                        symbols.join(", ")),
+                LoadError::AlreadyLoaded =>
+                    write!(f, "libvosk is already loaded")
+            }
+        }
+    }
+
+    impl Error for LoadError {}
+
+    fn dlerror() -> String {
+        unsafe {
+            let e = libc::dlerror();
+
+            if e.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(e).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    // Declares the libvosk functions we use. Each becomes a field of
+    // `Library`, resolved once in `load`, and a free function of the
+    // same name that calls through it, so the rest of the module can
+    // use them like plain `extern` declarations.
+    macro_rules! vosk_functions {
+        ($(fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+            struct Library {
+                $($name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)*
+            }
+
+            impl Library {
+                unsafe fn resolve(handle: *mut c_void, path: &Path) -> Result<Library, LoadError> {
+                    let mut missing = Vec::new();
+
+                    // Look up every symbol before failing so that the
+                    // error lists all of them
+                    $(
+                        let $name = libc::dlsym(handle, concat!(stringify!($name), "\0").as_ptr() as *const c_char);
+                        if $name.is_null() {
+                            missing.push(stringify!($name));
+                        }
+                    )*
+
+                    if !missing.is_empty() {
+                        return Err(LoadError::MissingSymbols { path: path.to_owned(), symbols: missing });
+                    }
+
+                    Ok(
+                        Library {
+                            $(
+                                $name: std::mem::transmute::<*mut c_void, unsafe extern "C" fn($($ty),*) $(-> $ret)?>($name),
+                            )*
+                        }
+                    )
+                }
+            }
+
+            $(
+                pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                    (library().$name)($($arg),*)
+                }
+            )*
+        }
+    }
+
+    vosk_functions! {
+        fn vosk_set_log_level(log_level: c_int);
+
+        fn vosk_model_new(path: *const c_char) -> *mut VoskModel;
+        fn vosk_recognizer_new(model: *mut VoskModel, sample_rate: f32) -> *mut VoskRecognizer;
+        fn vosk_recognizer_new_grm(model: *mut VoskModel, sample_rate: f32, grammar: *const c_char) -> *mut VoskRecognizer;
+
+        fn vosk_model_find_word(model: *mut VoskModel, word: *const c_char) -> c_int;
+
+        fn vosk_spk_model_new(path: *const c_char) -> *mut VoskSpkModel;
+        fn vosk_recognizer_set_spk_model(rec: *mut VoskRecognizer, spk_model: *mut VoskSpkModel);
+
+        fn vosk_recognizer_accept_waveform_s(rec: *mut VoskRecognizer, buf: *const c_short, len: usize) -> c_int;
+        fn vosk_recognizer_result(rec: *mut VoskRecognizer) -> *mut c_char;
+        fn vosk_recognizer_partial_result(rec: *mut VoskRecognizer) -> *mut c_char;
+        fn vosk_recognizer_final_result(rec: *mut VoskRecognizer) -> *mut c_char;
+        fn vosk_recognizer_set_max_alternatives(rec: *mut VoskRecognizer, n: c_int);
+        fn vosk_recognizer_set_words(rec: *mut VoskRecognizer, words: c_int);
+        fn vosk_recognizer_set_partial_words(rec: *mut VoskRecognizer, partial_words: c_int);
+        fn vosk_recognizer_set_endpointer_mode(rec: *mut VoskRecognizer, mode: c_int);
+        fn vosk_recognizer_set_endpointer_delays(rec: *mut VoskRecognizer, t_start_max: f32, t_end: f32, t_max: f32);
+        fn vosk_recognizer_reset(rec: *mut VoskRecognizer);
+
+        fn vosk_recognizer_free(rec: *mut VoskRecognizer);
+        fn vosk_model_free(model: *mut VoskModel);
+        fn vosk_spk_model_free(model: *mut VoskSpkModel);
+    }
+
+    fn library() -> &'static Library {
+        LIBRARY.get().expect("libvosk used before vosk::sys::load")
+    }
+
+    /// Load libvosk from `path`, or from the default library search
+    /// path if `None`. Must be called before anything else in this
+    /// module. The library is never unloaded.
+    pub fn load(path: Option<&Path>) -> Result<(), LoadError> {
+        use std::os::unix::ffi::OsStrExt;
+
+        if LIBRARY.get().is_some() {
+            return Err(LoadError::AlreadyLoaded);
+        }
+
+        let path = path.unwrap_or_else(|| Path::new("libvosk.so"));
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| LoadError::Open { path: path.to_owned(), reason: "path contains a NUL byte".into() })?;
+
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+
+        if handle.is_null() {
+            return Err(LoadError::Open { path: path.to_owned(), reason: dlerror() });
+        }
+
+        let lib = unsafe { Library::resolve(handle, path)? };
+
+        LIBRARY.set(lib).map_err(|_| LoadError::AlreadyLoaded)
+    }
+}
+
+/// libvosk writes its diagnostics (through Kaldi) straight to stderr.
+/// To explain failures, stderr is pointed at a pipe while a call that
+/// may fail is running, and libvosk's lines are picked out of it.
+pub mod log {
+    use std::fmt;
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::raw::c_int;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::Mutex;
+    use std::thread::JoinHandle;
+
+    // Only one redirection of stderr at a time
+    static CAPTURE_LOCK: Mutex<()> = Mutex::new(());
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Level {
+        Log,
+        Warning,
+        Error
+    }
+
+    /// One line of libvosk's log
+    #[derive(Debug, Clone)]
+    pub struct Message {
+        pub level: Level,
+        pub text: String
+    }
+
+    impl Message {
+        // Kaldi lines look like
+        // `ERROR (VoskAPI:ReadDataFiles():model.cc:213) Failed to ...`
+        fn parse(line: &str) -> Option<Message> {
+            let (level, rest) = line.split_once(" (VoskAPI:")?;
+            let level = match level {
+                "ERROR" | "ASSERTION_FAILED" => Level::Error,
+                "WARNING" => Level::Warning,
+                "LOG" => Level::Log,
+                l if l.starts_with("VLOG") => Level::Log,
+                _ => return None
+            };
+            let text = rest.split_once(") ").map_or(rest, |(_, t)| t);
+
+            Some(Message { level, text: text.trim_end().to_owned() })
+        }
+    }
+
+    impl fmt::Display for Message {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.text)
+        }
+    }
+
+    /// The most severe messages in `log`, for error reports
+    pub fn describe(log: &[Message]) -> String {
+        let worst = match log.iter().map(|m| m.level).max() {
+            Some(l) if l > Level::Log => l,
+            _ => return "libvosk gave no details".into()
+        };
+
+        log
+            .iter()
+            .filter(|m| m.level == worst)
+            .map(|m| m.text.as_str())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// Set libvosk's log verbosity: 0 logs info and errors, less than
+    /// 0 only warnings and errors, and more than 0 debug output too
+    pub fn set_level(level: c_int) {
+        unsafe {
+            super::sys::vosk_set_log_level(level);
+        }
+    }
+
+    /// Run `f`, collecting what libvosk logs meanwhile. Everything is
+    /// still passed on to stderr, including output from other threads.
+    pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<Message>) {
+        let _guard = CAPTURE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let redirect = match Redirect::new() {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Could not capture libvosk's log: {}", e);
+                return (f(), Vec::new());
+            }
+        };
+
+        let res = f();
+
+        (res, redirect.finish())
+    }
 
-    #[link(name = "vosk")]
-    extern "C" {
-        pub fn vosk_model_new(path: *const c_char) -> *mut VoskModel;
-        pub fn vosk_recognizer_new(model: *mut VoskModel, sample_rate: f32) -> *mut VoskRecognizer;
+    struct Redirect {
+        // The real stderr, to be put back
+        saved: c_int,
+        reader: JoinHandle<Vec<Message>>
+    }
+
+    impl Redirect {
+        fn new() -> io::Result<Redirect> {
+            let mut fds = [0; 2];
+
+            unsafe {
+                if libc::pipe(fds.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let pipe = File::from_raw_fd(fds[0]);
+                let saved = libc::dup(libc::STDERR_FILENO);
+                let out = libc::dup(libc::STDERR_FILENO);
+
+                if saved < 0 || out < 0 || libc::dup2(fds[1], libc::STDERR_FILENO) < 0 {
+                    let e = io::Error::last_os_error();
+                    libc::close(fds[1]);
+                    libc::close(saved);
+                    libc::close(out);
+                    return Err(e);
+                }
+
+                // stderr is now the only write end, so the pipe closes
+                // once it is put back
+                libc::close(fds[1]);
+
+                let mut out = File::from_raw_fd(out);
+                let reader = std::thread::spawn(move || {
+                    let mut messages = Vec::new();
+
+                    for line in BufReader::new(pipe).split(b'\n') {
+                        let line = match line {
+                            Ok(l) => l,
+                            Err(_) => break
+                        };
+
+                        let _ = out.write_all(&line);
+                        let _ = out.write_all(b"\n");
 
-        pub fn vosk_model_find_word(model: *mut VoskModel, word: *const c_char) -> c_int;
-        
-        pub fn vosk_recognizer_accept_waveform(rec: *mut VoskRecognizer, buf: *const u8, len: usize) -> c_int;
-        pub fn vosk_recognizer_accept_waveform_s(rec: *mut VoskRecognizer, buf: *const c_short, len: usize) -> c_int;
-        pub fn vosk_recognizer_result(rec: *mut VoskRecognizer) -> *mut c_char;
-        pub fn vosk_recognizer_partial_result(rec: *mut VoskRecognizer) -> *mut c_char;
-        pub fn vosk_recognizer_final_result(rec: *mut VoskRecognizer) -> *mut c_char;
-        pub fn vosk_recognizer_set_max_alternatives(rec: *mut VoskRecognizer, n: c_int);
-        pub fn vosk_recognizer_reset(rec: *mut VoskRecognizer);
-        
-        pub fn vosk_recognizer_free(rec: *mut VoskRecognizer);
-        pub fn vosk_model_free(model: *mut VoskModel);
+                        if let Some(m) = Message::parse(&String::from_utf8_lossy(&line)) {
+                            messages.push(m);
+                        }
+                    }
+
+                    messages
+                });
+
+                Ok(Redirect { saved, reader })
+            }
+        }
+
+        fn finish(self) -> Vec<Message> {
+            unsafe {
+                libc::dup2(self.saved, libc::STDERR_FILENO);
+                libc::close(self.saved);
+            }
+
+            self.reader.join().unwrap_or_default()
+        }
     }
 }
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::{CString, CStr};
 use std::os::raw::{c_int, c_uint};
 use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+use std::error::Error;
+use std::fmt;
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::speech::{self, SpeechBackend, SpeechSession, SpeechError, SessionOptions, EndpointerMode};
+use crate::models::ModelRegistry;
 
 #[derive(Debug)]
 pub struct Model(NonNull<sys::VoskModel>);
@@ -43,18 +351,102 @@ pub struct Model(NonNull<sys::VoskModel>);
 unsafe impl Send for Model {}
 unsafe impl Sync for Model {}
 
-impl Model {
-    pub fn new(path: impl AsRef<Path>) -> Option<Model> {
-        use std::os::unix::ffi::OsStrExt;
-        let path = CString::new(path.as_ref().to_owned().as_os_str().as_bytes()).ok()?;
+/// Why a model could not be loaded
+#[derive(Debug)]
+pub enum ModelError {
+    /// The path cannot be passed to libvosk
+    InvalidPath(PathBuf),
+    NotFound(PathBuf),
+    /// A file every model of this kind needs is missing
+    MissingFile(PathBuf),
+    /// libvosk failed to load the model; `log` is what it reported
+    Failed { path: PathBuf, log: Vec<log::Message> }
+}
 
-        unsafe {
-            Some(Model(NonNull::new(sys::vosk_model_new(path.as_ptr()))?))
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::InvalidPath(path) => write!(f, "Model path {:?} contains a NUL byte", path),
+            ModelError::NotFound(path) => write!(f, "Model directory {:?} not found", path),
+            ModelError::MissingFile(path) => write!(f, "Model file {:?} not found", path),
+            ModelError::Failed { path, log } => {
+                write!(f, "Could not load vosk model from {:?}: {}", path, log::describe(log))
+            }
+        }
+    }
+}
+
+impl Error for ModelError {}
+
+fn c_path(path: &Path) -> Result<CString, ModelError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| ModelError::InvalidPath(path.to_owned()))
+}
+
+/// Check that `path` looks like a vosk model, without loading it.
+/// Models come in two layouts: newer ones with `am`, `conf` and
+/// `graph` directories, and older ones with everything at the top.
+pub fn check_model_dir(path: &Path) -> Result<(), ModelError> {
+    if !path.is_dir() {
+        return Err(ModelError::NotFound(path.to_owned()));
+    }
+
+    let (am, conf, graph) = if path.join("am").is_dir() {
+        (path.join("am"), path.join("conf"), path.join("graph"))
+    } else {
+        (path.to_owned(), path.to_owned(), path.to_owned())
+    };
+
+    for file in [am.join("final.mdl"), conf.join("mfcc.conf")] {
+        if !file.is_file() {
+            return Err(ModelError::MissingFile(file));
         }
     }
 
-    pub fn find_word(&mut self, word: &str) -> Option<c_uint> {
-        let word = CString::new(word).unwrap();
+    // Either a static graph or a dynamic one in two parts
+    if !graph.join("HCLG.fst").is_file() && !graph.join("HCLr.fst").is_file() {
+        return Err(ModelError::MissingFile(graph.join("HCLG.fst")));
+    }
+
+    Ok(())
+}
+
+/// The sample rate a model was trained at, from the
+/// `--sample-frequency` in its feature configuration
+pub fn model_sample_rate(path: &Path) -> Option<u32> {
+    let conf = if path.join("am").is_dir() {
+        path.join("conf").join("mfcc.conf")
+    } else {
+        path.join("mfcc.conf")
+    };
+
+    std::fs::read_to_string(conf)
+        .ok()?
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("--sample-frequency="))
+        .and_then(|r| r.trim().parse::<f32>().ok())
+        .map(|r| r as u32)
+}
+
+impl Model {
+    pub fn new(path: impl AsRef<Path>) -> Result<Model, ModelError> {
+        let path = path.as_ref();
+        check_model_dir(path)?;
+        let c_path = c_path(path)?;
+
+        let (model, log) = log::capture(|| unsafe { sys::vosk_model_new(c_path.as_ptr()) });
+
+        NonNull::new(model)
+            .map(Model)
+            .ok_or_else(|| ModelError::Failed { path: path.to_owned(), log })
+    }
+
+    /// The symbol of `word` in the model's vocabulary, or `None` if
+    /// the model cannot recognize it. Lookups only read the model, so
+    /// this is safe on a shared model.
+    pub fn find_word(&self, word: &str) -> Option<c_uint> {
+        let word = CString::new(word).ok()?;
 
         let res = unsafe {
             sys::vosk_model_find_word(self.0.as_ptr(), word.as_ptr())
@@ -76,25 +468,100 @@ impl Drop for Model {
     }
 }
 
+/// A speaker identification model, which makes recognizers report
+/// an x-vector (voiceprint) for each final result
+#[derive(Debug)]
+pub struct SpkModel(NonNull<sys::VoskSpkModel>);
+
+unsafe impl Send for SpkModel {}
+unsafe impl Sync for SpkModel {}
+
+impl SpkModel {
+    pub fn new(path: impl AsRef<Path>) -> Result<SpkModel, ModelError> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            return Err(ModelError::NotFound(path.to_owned()));
+        }
+
+        let c_path = c_path(path)?;
+
+        let (model, log) = log::capture(|| unsafe { sys::vosk_spk_model_new(c_path.as_ptr()) });
+
+        NonNull::new(model)
+            .map(SpkModel)
+            .ok_or_else(|| ModelError::Failed { path: path.to_owned(), log })
+    }
+}
+
+impl Drop for SpkModel {
+    fn drop(&mut self) {
+        unsafe {
+            sys::vosk_spk_model_free(self.0.as_ptr());
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct Recognizer(*mut sys::VoskRecognizer);
+pub struct Recognizer(*mut sys::VoskRecognizer, Arc<Model>);
 
 unsafe impl Send for Recognizer {}
 unsafe impl Sync for Recognizer {}
 
 impl Recognizer {
-    pub fn new(model: &Model, sample_rate: f32) -> Recognizer {
-        unsafe {
-            Recognizer(sys::vosk_recognizer_new(model.0.as_ptr(), sample_rate))
-        }
+    // Recognizers hold on to their model so that it outlives them even
+    // if it is swapped out or unloaded meanwhile
+
+    pub fn new(model: &Arc<Model>, sample_rate: f32) -> Result<Recognizer, RecognizerError> {
+        Recognizer::create(model, || unsafe { sys::vosk_recognizer_new(model.0.as_ptr(), sample_rate) })
     }
 
-    pub fn accept_waveform(&mut self, data: &[u8]) -> bool {
-        let res = unsafe {
-            sys::vosk_recognizer_accept_waveform(self.0, data.as_ptr(), data.len())
-        };
+    /// Create a recognizer that only produces the given phrases. An
+    /// `[unk]` phrase is added so that speech outside the list is not
+    /// forced onto it. Only models with a dynamic graph (most of the
+    /// small models) honour the grammar; others ignore it.
+    pub fn with_grammar(
+        model: &Arc<Model>,
+        sample_rate: f32,
+        phrases: &[impl AsRef<str>]
+    ) -> Result<Recognizer, RecognizerError>
+    {
+        let mut grammar: Vec<&str> = phrases.iter().map(|p| p.as_ref()).collect();
+        grammar.push("[unk]");
 
-        res == 1
+        // JSON escapes NUL, so this cannot fail
+        let grammar = CString::new(serde_json::to_string(&grammar).unwrap()).unwrap();
+
+        Recognizer::create(model, || unsafe {
+            sys::vosk_recognizer_new_grm(model.0.as_ptr(), sample_rate, grammar.as_ptr())
+        })
+    }
+
+    // Capturing the log redirects stderr for the whole process and
+    // makes every other capture wait, so it is only done to explain a
+    // failure, by trying again
+    fn create(
+        model: &Arc<Model>,
+        new: impl Fn() -> *mut sys::VoskRecognizer
+    ) -> Result<Recognizer, RecognizerError>
+    {
+        let mut rec = new();
+
+        if rec.is_null() {
+            let (retry, log) = log::capture(&new);
+
+            if retry.is_null() {
+                return Err(RecognizerError { log });
+            }
+
+            rec = retry;
+        }
+
+        Ok(Recognizer(rec, model.clone()))
+    }
+
+    pub fn model(&self) -> &Arc<Model> {
+        &self.1
     }
 
     pub fn accept_waveform_i16(&mut self, data: &[i16]) -> bool {
@@ -109,7 +576,7 @@ impl Recognizer {
     // the result buffer is stored in the `VoxRecognizer` itself
     // and set with every result call
 
-    pub fn result_json(&mut self) -> &CStr {
+    fn result_json(&mut self) -> &CStr {
         unsafe {
             let s = sys::vosk_recognizer_result(self.0);
 
@@ -117,7 +584,7 @@ impl Recognizer {
         }
     }
 
-    pub fn partial_result_json(&mut self) -> &CStr {
+    fn partial_result_json(&mut self) -> &CStr {
         unsafe {
             let s = sys::vosk_recognizer_partial_result(self.0);
 
@@ -125,7 +592,7 @@ impl Recognizer {
         }
     }
 
-    pub fn final_result_json(&mut self) -> &CStr {
+    fn final_result_json(&mut self) -> &CStr {
         unsafe {
             let s = sys::vosk_recognizer_final_result(self.0);
 
@@ -133,12 +600,74 @@ impl Recognizer {
         }
     }
 
+    /// The result of the segment ending at the last endpoint. The
+    /// recognizer keeps going with the rest of the stream.
+    pub fn result(&mut self) -> Result<RecognitionResult, ResultError> {
+        parse_result(self.result_json())
+    }
+
+    pub fn partial_result(&mut self) -> Result<PartialResult, ResultError> {
+        parse_result(self.partial_result_json())
+    }
+
+    /// Flush the remaining audio and return its result
+    pub fn final_result(&mut self) -> Result<RecognitionResult, ResultError> {
+        parse_result(self.final_result_json())
+    }
+
     pub fn set_max_alternatives(&mut self, n: c_int) {
         unsafe {
             sys::vosk_recognizer_set_max_alternatives(self.0, n);
         }
     }
 
+    /// Include per-word timings and confidences (the `result` array)
+    /// in final results
+    pub fn set_words(&mut self, words: bool) {
+        unsafe {
+            sys::vosk_recognizer_set_words(self.0, words as c_int);
+        }
+    }
+
+    /// Include per-word timings and confidences (the
+    /// `partial_result` array) in partial results
+    pub fn set_partial_words(&mut self, partial_words: bool) {
+        unsafe {
+            sys::vosk_recognizer_set_partial_words(self.0, partial_words as c_int);
+        }
+    }
+
+    /// Report x-vectors from `spk_model` in final results. libvosk
+    /// keeps a reference to the model, hence the `'static`.
+    pub fn set_spk_model(&mut self, spk_model: &'static SpkModel) {
+        unsafe {
+            sys::vosk_recognizer_set_spk_model(self.0, spk_model.0.as_ptr());
+        }
+    }
+
+    /// Scale the model's endpointing rules, which decide how long a
+    /// pause ends a segment. The rules are recomputed from the model's
+    /// each time, so this also undoes `set_endpointer_delays`.
+    pub fn set_endpointer_mode(&mut self, mode: EndpointerMode) {
+        let mode = match mode {
+            EndpointerMode::Default => sys::VOSK_EP_ANSWER_DEFAULT,
+            EndpointerMode::Short => sys::VOSK_EP_ANSWER_SHORT,
+            EndpointerMode::Long => sys::VOSK_EP_ANSWER_LONG,
+            EndpointerMode::VeryLong => sys::VOSK_EP_ANSWER_VERY_LONG
+        };
+
+        unsafe {
+            sys::vosk_recognizer_set_endpointer_mode(self.0, mode);
+        }
+    }
+
+    /// Set the endpointing rules directly; all times are in seconds
+    pub fn set_endpointer_delays(&mut self, start_max: f32, end: f32, max: f32) {
+        unsafe {
+            sys::vosk_recognizer_set_endpointer_delays(self.0, start_max, end, max);
+        }
+    }
+
     pub fn reset(&mut self) {
         unsafe {
             sys::vosk_recognizer_reset(self.0);
@@ -146,9 +675,100 @@ impl Recognizer {
     }
 }
 
-#[derive(Deserialize)]
-pub struct SimpleResult {
-    pub text: String
+/// libvosk could not create a recognizer; `log` is what it reported
+#[derive(Debug)]
+pub struct RecognizerError {
+    pub log: Vec<log::Message>
+}
+
+impl fmt::Display for RecognizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not create vosk recognizer: {}", log::describe(&self.log))
+    }
+}
+
+impl Error for RecognizerError {}
+
+fn parse_result<T: DeserializeOwned>(json: &CStr) -> Result<T, ResultError> {
+    serde_json::from_slice(json.to_bytes())
+        .map_err(|source| ResultError {
+            json: json.to_string_lossy().into_owned(),
+            source
+        })
+}
+
+/// A result from libvosk that did not have the expected shape
+#[derive(Debug)]
+pub struct ResultError {
+    pub json: String,
+    pub source: serde_json::Error
+}
+
+impl fmt::Display for ResultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed vosk result {:?}: {}", self.json, self.source)
+    }
+}
+
+impl Error for ResultError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// A single recognized word. `start` and `end` are in seconds from
+/// the start of the utterance; `conf` is missing for words inside
+/// alternatives.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Word {
+    pub word: String,
+    pub start: f32,
+    pub end: f32,
+    pub conf: Option<f32>
+}
+
+/// A final result. `result` is only filled in if words were enabled
+/// with `Recognizer::set_words`, and `spk` (the speaker x-vector) and
+/// `spk_frames` (the number of 10ms frames it was computed over) only
+/// if the recognizer has a speaker model.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompleteResult {
+    pub text: String,
+    #[serde(default)]
+    pub result: Vec<Word>,
+    pub spk: Option<Vec<f32>>,
+    pub spk_frames: Option<u32>
+}
+
+/// One candidate reading, produced when the recognizer has been
+/// configured with `set_max_alternatives`. `confidence` is an
+/// unnormalized score; higher is better.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Alternative {
+    pub confidence: f32,
+    pub text: String,
+    #[serde(default)]
+    pub result: Vec<Word>
+}
+
+/// A final result, whose shape depends on whether alternatives are
+/// enabled
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RecognitionResult {
+    Alternatives {
+        alternatives: Vec<Alternative>
+    },
+    Single(CompleteResult)
+}
+
+/// A partial result. `partial_result` is only filled in if words were
+/// enabled with `Recognizer::set_partial_words`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PartialResult {
+    pub partial: String,
+    #[serde(default)]
+    pub partial_result: Vec<Word>
 }
 
 impl Drop for Recognizer {
@@ -158,3 +778,268 @@ impl Drop for Recognizer {
         }
     }
 }
+
+impl From<Word> for speech::Word {
+    fn from(w: Word) -> speech::Word {
+        speech::Word {
+            word: w.word,
+            start: w.start,
+            end: w.end,
+            conf: w.conf
+        }
+    }
+}
+
+impl From<RecognitionResult> for speech::Transcript {
+    fn from(r: RecognitionResult) -> speech::Transcript {
+        match r {
+            RecognitionResult::Single(r) => speech::Transcript {
+                text: r.text,
+                words: r.result.into_iter().map(Into::into).collect(),
+                alternatives: Vec::new(),
+                voiceprint: r.spk.map(|xvector| speech::Voiceprint {
+                    xvector,
+                    frames: r.spk_frames.unwrap_or(0)
+                })
+            },
+            RecognitionResult::Alternatives { alternatives } => {
+                let alternatives: Vec<speech::Alternative> = alternatives
+                    .into_iter()
+                    .map(|a| speech::Alternative {
+                        text: a.text,
+                        words: a.result.into_iter().map(Into::into).collect(),
+                        confidence: a.confidence
+                    })
+                    .collect();
+
+                let (text, words) = alternatives
+                    .first()
+                    .map(|a| (a.text.clone(), a.words.clone()))
+                    .unwrap_or_default();
+
+                speech::Transcript {
+                    text,
+                    words,
+                    alternatives,
+                    voiceprint: None
+                }
+            }
+        }
+    }
+}
+
+impl SpeechSession for Recognizer {
+    fn accept_samples(&mut self, samples: &[i16]) -> bool {
+        self.accept_waveform_i16(samples)
+    }
+
+    fn partial(&mut self) -> Result<speech::Partial, SpeechError> {
+        let p = self.partial_result()?;
+
+        Ok(
+            speech::Partial {
+                text: p.partial,
+                words: p.partial_result.into_iter().map(Into::into).collect()
+            }
+        )
+    }
+
+    fn result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        Ok(Recognizer::result(self)?.into())
+    }
+
+    fn final_result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        Ok(Recognizer::final_result(self)?.into())
+    }
+
+    fn reset(&mut self) {
+        Recognizer::reset(self)
+    }
+}
+
+/// What a recognizer was created with that cannot be changed
+/// afterwards. Recognizers are only reused for sessions with the same
+/// configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RecognizerConfig {
+    pub sample_rate: u32,
+    pub vocabulary: Option<Vec<String>>,
+    // libvosk has no way to remove a speaker model again
+    pub speaker_id: bool
+}
+
+/// A recognizer borrowed from a `ModelRegistry`, which gets it back
+/// (reset) when the session is dropped
+struct PooledRecognizer {
+    // Only `None` while being dropped
+    rec: Option<Recognizer>,
+    config: RecognizerConfig,
+    path: PathBuf,
+    models: Arc<ModelRegistry>
+}
+
+impl PooledRecognizer {
+    fn rec(&mut self) -> &mut Recognizer {
+        self.rec.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledRecognizer {
+    fn drop(&mut self) {
+        if let Some(mut rec) = self.rec.take() {
+            rec.reset();
+            self.models.put_recognizer(&self.path, self.config.clone(), rec);
+        }
+    }
+}
+
+impl SpeechSession for PooledRecognizer {
+    fn accept_samples(&mut self, samples: &[i16]) -> bool {
+        self.rec().accept_samples(samples)
+    }
+
+    fn partial(&mut self) -> Result<speech::Partial, SpeechError> {
+        SpeechSession::partial(self.rec())
+    }
+
+    fn result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        SpeechSession::result(self.rec())
+    }
+
+    fn final_result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        SpeechSession::final_result(self.rec())
+    }
+
+    fn reset(&mut self) {
+        self.rec().reset()
+    }
+}
+
+/// Speech backend running vosk models from a `ModelRegistry`. Each
+/// new session uses the registry's current model, so reloaded models
+/// are picked up between utterances.
+pub struct VoskBackend {
+    models: Arc<ModelRegistry>,
+    path: PathBuf,
+    // The newest model we have seen for `path`. Holding on to it keeps
+    // it loaded for as long as the backend is in use.
+    model: Mutex<Arc<Model>>,
+    sample_rate: u32,
+    spk_model: Option<&'static SpkModel>
+}
+
+impl VoskBackend {
+    /// `model` must have been loaded by `models` for `lang`
+    pub fn new(
+        models: Arc<ModelRegistry>,
+        lang: Option<&str>,
+        model: Arc<Model>,
+        spk_model: Option<&'static SpkModel>
+    ) -> VoskBackend
+    {
+        let path = models.path_for(lang).to_owned();
+
+        VoskBackend {
+            sample_rate: models.sample_rate(&path),
+            path,
+            models,
+            model: Mutex::new(model),
+            spk_model
+        }
+    }
+
+    fn current_model(&self) -> Arc<Model> {
+        let mut model = self.model.lock().unwrap();
+
+        if let Some(current) = self.models.current(&self.path) {
+            *model = current;
+        }
+
+        model.clone()
+    }
+}
+
+impl VoskBackend {
+    fn recognizer(&self, model: &Arc<Model>, config: &RecognizerConfig) -> Result<Recognizer, RecognizerError> {
+        if let Some(rec) = self.models.take_recognizer(&self.path, model, config) {
+            return Ok(rec);
+        }
+
+        let sample_rate = config.sample_rate as f32;
+        let mut rec = match &config.vocabulary {
+            Some(phrases) => Recognizer::with_grammar(model, sample_rate, phrases)?,
+            None => Recognizer::new(model, sample_rate)?
+        };
+
+        if let (true, Some(spk_model)) = (config.speaker_id, self.spk_model) {
+            rec.set_spk_model(spk_model);
+        }
+
+        Ok(rec)
+    }
+
+    fn config(&self, sample_rate: f32, options: &SessionOptions) -> RecognizerConfig {
+        RecognizerConfig {
+            sample_rate: sample_rate as u32,
+            vocabulary: options.vocabulary.clone(),
+            speaker_id: options.speaker_id && self.spk_model.is_some()
+        }
+    }
+}
+
+impl SpeechBackend for VoskBackend {
+    fn new_session(&self, sample_rate: f32, options: &SessionOptions) -> Result<Box<dyn SpeechSession>, SpeechError> {
+        let model = self.current_model();
+        let config = self.config(sample_rate, options);
+        let mut rec = self.recognizer(&model, &config)?;
+
+        // These can change between uses, so are always set
+        rec.set_words(options.words);
+        rec.set_partial_words(options.words);
+        rec.set_max_alternatives(options.max_alternatives as c_int);
+        rec.set_endpointer_mode(options.endpointer.mode);
+
+        if let Some(d) = options.endpointer.delays {
+            rec.set_endpointer_delays(d.start_max, d.end, d.max);
+        }
+
+        Ok(
+            Box::new(
+                PooledRecognizer {
+                    rec: Some(rec),
+                    config,
+                    path: self.path.clone(),
+                    models: self.models.clone()
+                }
+            )
+        )
+    }
+
+    fn warm_up(&self, sample_rate: f32, options: &SessionOptions) {
+        let model = self.current_model();
+        let config = self.config(sample_rate, options);
+        let mut rec = match self.recognizer(&model, &config) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Could not warm up: {}", e);
+                return;
+            }
+        };
+
+        // The first audio through a recognizer sets up caches in the
+        // decoder, which otherwise delays the first words
+        rec.accept_waveform_i16(&vec![0; sample_rate as usize / 10]);
+        let _ = rec.final_result_json();
+        rec.reset();
+
+        self.models.put_recognizer(&self.path, config, rec);
+    }
+
+    fn sample_rate(&self) -> Option<f32> {
+        Some(self.sample_rate as f32)
+    }
+
+    fn identifies_speakers(&self) -> bool {
+        self.spk_model.is_some()
+    }
+}
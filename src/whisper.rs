@@ -0,0 +1,377 @@
+pub mod sys {
+    use std::os::raw::{c_char, c_float, c_int, c_void};
+
+    #[repr(C)]
+    pub struct WhisperContext {
+        _data: [u8; 0]
+    }
+    #[repr(C)]
+    pub struct WhisperState {
+        _data: [u8; 0]
+    }
+
+    pub const WHISPER_SAMPLING_GREEDY: c_int = 0;
+
+    pub const WHISPER_SAMPLE_RATE: u32 = 16_000;
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct WhisperContextParams {
+        pub use_gpu: bool
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct WhisperGreedyParams {
+        pub best_of: c_int
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct WhisperBeamSearchParams {
+        pub beam_size: c_int,
+        pub patience: c_float
+    }
+
+    // Mirrors `struct whisper_full_params` from whisper.h as of
+    // whisper.cpp v1.5.4. It is passed by value, so this must be kept
+    // in sync with the library we link against.
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct WhisperFullParams {
+        pub strategy: c_int,
+
+        pub n_threads: c_int,
+        pub n_max_text_ctx: c_int,
+        pub offset_ms: c_int,
+        pub duration_ms: c_int,
+
+        pub translate: bool,
+        pub no_context: bool,
+        pub no_timestamps: bool,
+        pub single_segment: bool,
+        pub print_special: bool,
+        pub print_progress: bool,
+        pub print_realtime: bool,
+        pub print_timestamps: bool,
+
+        pub token_timestamps: bool,
+        pub thold_pt: c_float,
+        pub thold_ptsum: c_float,
+        pub max_len: c_int,
+        pub split_on_word: bool,
+        pub max_tokens: c_int,
+
+        pub speed_up: bool,
+        pub debug_mode: bool,
+        pub audio_ctx: c_int,
+
+        pub tdrz_enable: bool,
+
+        pub initial_prompt: *const c_char,
+        pub prompt_tokens: *const c_int,
+        pub prompt_n_tokens: c_int,
+
+        pub language: *const c_char,
+        pub detect_language: bool,
+
+        pub suppress_blank: bool,
+        pub suppress_non_speech_tokens: bool,
+
+        pub temperature: c_float,
+        pub max_initial_ts: c_float,
+        pub length_penalty: c_float,
+
+        pub temperature_inc: c_float,
+        pub entropy_thold: c_float,
+        pub logprob_thold: c_float,
+        pub no_speech_thold: c_float,
+
+        pub greedy: WhisperGreedyParams,
+        pub beam_search: WhisperBeamSearchParams,
+
+        pub new_segment_callback: *mut c_void,
+        pub new_segment_callback_user_data: *mut c_void,
+
+        pub progress_callback: *mut c_void,
+        pub progress_callback_user_data: *mut c_void,
+
+        pub encoder_begin_callback: *mut c_void,
+        pub encoder_begin_callback_user_data: *mut c_void,
+
+        pub abort_callback: *mut c_void,
+        pub abort_callback_user_data: *mut c_void,
+
+        pub logits_filter_callback: *mut c_void,
+        pub logits_filter_callback_user_data: *mut c_void,
+
+        pub grammar_rules: *const *const c_void,
+        pub n_grammar_rules: usize,
+        pub i_start_rule: usize,
+        pub grammar_penalty: c_float
+    }
+
+    #[link(name = "whisper")]
+    extern "C" {
+        pub fn whisper_init_from_file_with_params_no_state(path: *const c_char, params: WhisperContextParams) -> *mut WhisperContext;
+        pub fn whisper_init_state(ctx: *mut WhisperContext) -> *mut WhisperState;
+
+        pub fn whisper_full_default_params(strategy: c_int) -> WhisperFullParams;
+        pub fn whisper_full_with_state(
+            ctx: *mut WhisperContext,
+            state: *mut WhisperState,
+            params: WhisperFullParams,
+            samples: *const c_float,
+            n_samples: c_int
+        ) -> c_int;
+
+        pub fn whisper_full_n_segments_from_state(state: *mut WhisperState) -> c_int;
+        pub fn whisper_full_get_segment_text_from_state(state: *mut WhisperState, i_segment: c_int) -> *const c_char;
+        pub fn whisper_full_get_segment_t0_from_state(state: *mut WhisperState, i_segment: c_int) -> i64;
+        pub fn whisper_full_get_segment_t1_from_state(state: *mut WhisperState, i_segment: c_int) -> i64;
+
+        pub fn whisper_free_state(state: *mut WhisperState);
+        pub fn whisper_free(ctx: *mut WhisperContext);
+    }
+}
+
+use std::path::Path;
+use std::ffi::{CString, CStr, NulError};
+use std::os::raw::c_int;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use crate::speech::{self, SpeechBackend, SpeechSession, SpeechError, SessionOptions};
+
+/// A loaded whisper model. The weights are shared; each transcription
+/// gets its own `State`.
+#[derive(Debug)]
+pub struct Context(NonNull<sys::WhisperContext>);
+
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl Context {
+    pub fn new(path: impl AsRef<Path>) -> Option<Context> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = CString::new(path.as_ref().to_owned().as_os_str().as_bytes()).ok()?;
+        let params = sys::WhisperContextParams { use_gpu: false };
+
+        unsafe {
+            Some(Context(NonNull::new(sys::whisper_init_from_file_with_params_no_state(path.as_ptr(), params))?))
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            sys::whisper_free(self.0.as_ptr());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct State(NonNull<sys::WhisperState>);
+
+unsafe impl Send for State {}
+
+impl State {
+    pub fn new(ctx: &Context) -> Option<State> {
+        unsafe {
+            Some(State(NonNull::new(sys::whisper_init_state(ctx.0.as_ptr()))?))
+        }
+    }
+
+    /// Transcribe 16kHz mono `samples`, returning each segment's text
+    /// with its start and end in seconds
+    pub fn transcribe(
+        &mut self,
+        ctx: &Context,
+        samples: &[f32],
+        language: Option<&CStr>,
+        n_threads: c_int
+    ) -> Result<Vec<(String, f32, f32)>, WhisperError>
+    {
+        let res = unsafe {
+            let mut params = sys::whisper_full_default_params(sys::WHISPER_SAMPLING_GREEDY);
+            params.n_threads = n_threads;
+            params.print_progress = false;
+            params.print_realtime = false;
+            params.print_timestamps = false;
+            params.language = language.map_or(std::ptr::null(), |l| l.as_ptr());
+
+            sys::whisper_full_with_state(
+                ctx.0.as_ptr(),
+                self.0.as_ptr(),
+                params,
+                samples.as_ptr(),
+                samples.len() as c_int)
+        };
+
+        if res != 0 {
+            return Err(WhisperError(res));
+        }
+
+        let n = unsafe { sys::whisper_full_n_segments_from_state(self.0.as_ptr()) };
+
+        Ok(
+            (0..n)
+                .map(|i| unsafe {
+                    let text = CStr::from_ptr(sys::whisper_full_get_segment_text_from_state(self.0.as_ptr(), i));
+                    // Segment times are in centiseconds
+                    let t0 = sys::whisper_full_get_segment_t0_from_state(self.0.as_ptr(), i);
+                    let t1 = sys::whisper_full_get_segment_t1_from_state(self.0.as_ptr(), i);
+
+                    (text.to_string_lossy().trim().to_owned(), t0 as f32 / 100.0, t1 as f32 / 100.0)
+                })
+                .collect()
+        )
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        unsafe {
+            sys::whisper_free_state(self.0.as_ptr());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WhisperError(pub c_int);
+
+impl std::fmt::Display for WhisperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "whisper_full failed with code {}", self.0)
+    }
+}
+
+impl std::error::Error for WhisperError {}
+
+// Whisper only takes 16kHz input. Voice messages are not latency
+// sensitive, so plain linear interpolation is good enough here.
+fn resample_linear(samples: &[i16], from: f32) -> Vec<f32> {
+    let to = sys::WHISPER_SAMPLE_RATE as f32;
+    let step = from / to;
+    let len = (samples.len() as f32 / step) as usize;
+
+    (0..len)
+        .map(|i| {
+            let pos = i as f32 * step;
+            let idx = pos as usize;
+            let frac = pos - idx as f32;
+            let a = samples[idx] as f32;
+            let b = *samples.get(idx + 1).unwrap_or(&samples[idx]) as f32;
+
+            (a + (b - a) * frac) / 32768.0
+        })
+        .collect()
+}
+
+/// Speech backend running whisper.cpp on the CPU. Whisper works on
+/// whole recordings, so sessions buffer all audio and only produce
+/// text from `final_result`; this makes it a poor fit for live
+/// captions but a good one for voice messages.
+pub struct WhisperBackend {
+    ctx: Arc<Context>,
+    language: Option<CString>,
+    n_threads: c_int
+}
+
+impl WhisperBackend {
+    /// `language` is a whisper language code such as "en"; `None`
+    /// lets whisper detect it. Fails if it has a NUL in it.
+    pub fn new(ctx: Context, language: Option<&str>) -> Result<WhisperBackend, NulError> {
+        let n_threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(8) as c_int;
+
+        Ok(
+            WhisperBackend {
+                ctx: Arc::new(ctx),
+                language: language.map(CString::new).transpose()?,
+                n_threads
+            }
+        )
+    }
+}
+
+impl SpeechBackend for WhisperBackend {
+    fn new_session(&self, sample_rate: f32, _options: &SessionOptions) -> Result<Box<dyn SpeechSession>, SpeechError> {
+        let state = State::new(&self.ctx).ok_or("Could not create whisper state")?;
+
+        Ok(
+            Box::new(
+                WhisperSession {
+                    state,
+                    ctx: self.ctx.clone(),
+                    language: self.language.clone(),
+                    n_threads: self.n_threads,
+                    sample_rate,
+                    samples: Vec::new()
+                }
+            )
+        )
+    }
+}
+
+struct WhisperSession {
+    // Declared before `ctx` so it is freed first
+    state: State,
+    ctx: Arc<Context>,
+    language: Option<CString>,
+    n_threads: c_int,
+    sample_rate: f32,
+    samples: Vec<i16>
+}
+
+impl SpeechSession for WhisperSession {
+    fn accept_samples(&mut self, samples: &[i16]) -> bool {
+        self.samples.extend_from_slice(samples);
+
+        false
+    }
+
+    fn partial(&mut self) -> Result<speech::Partial, SpeechError> {
+        Ok(Default::default())
+    }
+
+    fn result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        // There are never any endpoints, so never a finished segment
+        Ok(Default::default())
+    }
+
+    fn final_result(&mut self) -> Result<speech::Transcript, SpeechError> {
+        if self.samples.is_empty() {
+            return Ok(Default::default());
+        }
+
+        let samples = resample_linear(&self.samples, self.sample_rate);
+        self.samples.clear();
+
+        let segments = self.state.transcribe(
+            &self.ctx,
+            &samples,
+            self.language.as_deref(),
+            self.n_threads)?;
+
+        let text = segments
+            .iter()
+            .map(|(t, _, _)| t.as_str())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok(
+            speech::Transcript {
+                text,
+                ..Default::default()
+            }
+        )
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::speech::{SpeechBackend, SpeechError, SessionOptions, Transcript, Partial};
+
+// Packets of audio a speaker's worker may fall behind by (20ms each)
+// before new audio is dropped
+const WORKER_QUEUE_LEN: usize = 50;
+
+// Seconds of audio between looks at the partial result
+const PARTIAL_INTERVAL_SECS: f32 = 0.4;
+
+/// Results from a worker as they come
+#[derive(Debug)]
+pub enum WorkerEvent {
+    /// The reading so far of the segment in progress, when it changes
+    Partial(Partial),
+    /// A finished segment
+    Segment(Transcript)
+}
+
+/// Runs speech recognition on blocking threads so that it never
+/// stalls the async runtime. Each live speaker gets a worker of its
+/// own; at most `threads` of them (plus one-off jobs) do recognition at
+/// any one time, and the rest wait their turn.
+pub struct RecognitionPool {
+    permits: Arc<Semaphore>
+}
+
+impl RecognitionPool {
+    pub fn new(threads: usize) -> RecognitionPool {
+        RecognitionPool {
+            permits: Arc::new(Semaphore::new(threads.max(1)))
+        }
+    }
+
+    /// Run a one-off job, such as transcribing a voice message
+    pub async fn run<T, F>(&self, f: F) -> Result<T, SpeechError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static
+    {
+        let _permit = self.permits.acquire().await?;
+
+        Ok(tokio::task::spawn_blocking(f).await?)
+    }
+
+    /// Start a worker that recognizes one speaker's audio with a new
+    /// session from `backend`
+    pub fn spawn_worker(
+        &self,
+        backend: Arc<dyn SpeechBackend>,
+        sample_rate: f32,
+        options: SessionOptions
+    ) -> SpeakerWorker
+    {
+        let (tx, rx) = mpsc::channel(WORKER_QUEUE_LEN);
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let permits = self.permits.clone();
+        let handle = Handle::current();
+
+        tokio::task::spawn_blocking(move || {
+            run_worker(rx, events_tx, permits, handle, backend, sample_rate, options)
+        });
+
+        SpeakerWorker {
+            tx,
+            events,
+            dropped: AtomicUsize::new(0)
+        }
+    }
+}
+
+enum Command {
+    Audio(Vec<i16>),
+    Finish(oneshot::Sender<Result<Transcript, SpeechError>>)
+}
+
+fn run_worker(
+    mut rx: mpsc::Receiver<Command>,
+    events: mpsc::UnboundedSender<WorkerEvent>,
+    permits: Arc<Semaphore>,
+    handle: Handle,
+    backend: Arc<dyn SpeechBackend>,
+    sample_rate: f32,
+    options: SessionOptions
+)
+{
+    let mut session = match backend.new_session(sample_rate, &options) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not start recognition session: {}", e);
+            // Dropping `rx` makes the owner's `finish` fail
+            return;
+        }
+    };
+
+    // Samples since the last segment ended
+    let mut segment_len = 0;
+    let max_segment_len = options.max_segment_secs.map(|s| (s * sample_rate) as usize);
+    // Samples since the partial result was last checked, and what it
+    // said then
+    let mut since_partial = 0;
+    let mut last_partial = String::new();
+    let partial_interval = (PARTIAL_INTERVAL_SECS * sample_rate) as usize;
+
+    while let Some(cmd) = rx.blocking_recv() {
+        let _permit = match handle.block_on(permits.acquire()) {
+            Ok(p) => p,
+            Err(_) => return
+        };
+
+        match cmd {
+            Command::Audio(samples) => {
+                segment_len += samples.len();
+                since_partial += samples.len();
+
+                let endpoint = session.accept_samples(&samples);
+                let too_long = max_segment_len.map_or(false, |max| segment_len >= max);
+
+                if endpoint || too_long {
+                    segment_len = 0;
+                    since_partial = 0;
+                    last_partial.clear();
+
+                    // Without an endpoint there is no finished segment
+                    // to take, so one is forced by flushing; the
+                    // session carries on after that, so long stretches
+                    // without a pause still get captioned as they go
+                    let result = if endpoint {
+                        session.result()
+                    } else {
+                        session.final_result()
+                    };
+
+                    match result {
+                        Ok(t) if !t.text.is_empty() => {
+                            let _ = events.send(WorkerEvent::Segment(t));
+                        },
+                        Ok(_) => {},
+                        Err(e) => eprintln!("Dropping segment: {}", e)
+                    }
+                } else if since_partial >= partial_interval {
+                    since_partial = 0;
+
+                    match session.partial() {
+                        Ok(p) if !p.text.is_empty() && p.text != last_partial => {
+                            last_partial = p.text.clone();
+                            let _ = events.send(WorkerEvent::Partial(p));
+                        },
+                        Ok(_) => {},
+                        Err(e) => eprintln!("Could not get partial result: {}", e)
+                    }
+                }
+            },
+            Command::Finish(reply) => {
+                let _ = reply.send(session.final_result());
+                return;
+            }
+        }
+    }
+}
+
+/// Handle to one speaker's recognition worker. The worker stops when
+/// this is finished or dropped.
+pub struct SpeakerWorker {
+    tx: mpsc::Sender<Command>,
+    // Results ahead of the final one
+    events: mpsc::UnboundedReceiver<WorkerEvent>,
+    dropped: AtomicUsize
+}
+
+impl SpeakerWorker {
+    /// Queue audio for recognition. If the worker has fallen too far
+    /// behind, the audio is dropped rather than queued without bound;
+    /// returns false in that case.
+    pub fn push_audio(&self, samples: Vec<i16>) -> bool {
+        match self.tx.try_send(Command::Audio(samples)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                // Only complain once per utterance so the log stays usable
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!("Recognition is falling behind; dropping audio");
+                }
+                false
+            },
+            Err(TrySendError::Closed(_)) => false
+        }
+    }
+
+    /// Number of audio packets dropped so far because of overload
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Results since the last call
+    pub fn events(&mut self) -> Vec<WorkerEvent> {
+        let mut res = Vec::new();
+
+        while let Ok(e) = self.events.try_recv() {
+            res.push(e);
+        }
+
+        res
+    }
+
+    /// Wait for the queued audio to be recognized and return the
+    /// results not yet collected, the last being the segment with the
+    /// rest of the utterance if anything was recognized in it
+    pub async fn finish(mut self) -> Result<Vec<WorkerEvent>, SpeechError> {
+        let (reply, rx) = oneshot::channel();
+
+        self.tx
+            .send(Command::Finish(reply))
+            .await
+            .map_err(|_| "Recognition worker stopped")?;
+
+        let last = rx.await.map_err(|_| "Recognition worker stopped")??;
+        // The worker sends all other results before replying
+        let mut res = self.events();
+        if !last.text.is_empty() {
+            res.push(WorkerEvent::Segment(last));
+        }
+
+        Ok(res)
+    }
+}